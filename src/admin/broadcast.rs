@@ -9,10 +9,16 @@ pub async fn broadcast(
     Json(message): Json<InternalMessages>,
 ) -> Result<&'static str> {
     if let InternalMessages::BroadCastMessage { message, to } = message {
-        state
-            .tx
-            .send(InternalMessages::BroadCastMessage { message, to })
-            .unwrap();
+        let msg = InternalMessages::BroadCastMessage {
+            message,
+            to: to.clone(),
+        };
+        // Only messages for everyone go through the broadcast channel
+        if to.is_empty() {
+            state.broadcast(msg);
+        } else {
+            state.sessions.send_many(&to, msg);
+        }
     };
     Ok("Ok")
 }
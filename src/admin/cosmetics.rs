@@ -6,7 +6,9 @@ use serde::Deserialize;
 use crate::{
     app_state::{AppState, Cosmetic},
     bitflags::CosmeticFlags,
+    cosmetic_log::CosmeticChange,
     error::Result,
+    messages::InternalMessages,
     utils::retrieve_cosmetics::retrieve_cosmetics,
 };
 
@@ -33,28 +35,37 @@ pub async fn get_cosmetics<'a>(State(state): State<Arc<AppState>>) -> Json<Vec<C
 
 pub async fn add_cosmetic(State(state): State<Arc<AppState>>, Json(data): Json<AddCosmetic>) -> &'static str {
     let mut cosmetics = state.cosmetics.lock();
-    cosmetics.push(Cosmetic {
+    let cosmetic = Cosmetic {
         id: data.id,
         name: data.name,
         description: data.description,
         data: data.data,
         type_field: data.type_field,
         required_flags: data.required_flags,
-    });
+    };
+    state.persist_cosmetic(&cosmetic);
+    cosmetics.push(cosmetic.clone());
+    state.record_cosmetic_change(CosmeticChange::Added { cosmetic });
 
     "ok"
 }
 
 pub async fn remove_cosmetic(State(state): State<Arc<AppState>>, Query(data): Query<DeleteCosmetic>) -> &'static str {
     let mut cosmetics = state.cosmetics.lock();
+    let len = cosmetics.len();
     cosmetics.retain(|c| c.id != data.id);
+    state.forget_cosmetic(data.id);
+    if cosmetics.len() != len {
+        state.record_cosmetic_change(CosmeticChange::Removed { id: data.id });
+    }
     "ok"
 }
 
 pub async fn force_update(State(state): State<Arc<AppState>>) -> Result<&'static str> {
     println!("Updating cosmetics");
-    let cosmetics = retrieve_cosmetics().await;
-    state.cosmetics.lock().clone_from(&cosmetics.cosmetics);
-    state.users.lock().clone_from(&cosmetics.users);
+    let cosmetics = retrieve_cosmetics(&*state.storage);
+    state.replace_cosmetics(cosmetics);
+    // The cosmetic log was reset, clients following it have to sync again
+    state.broadcast(InternalMessages::CosmeticsAck);
     Ok("Ok")
 }
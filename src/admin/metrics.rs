@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
 
 use axum::{extract::State, response::IntoResponse};
 use procfs::process::LimitValue;
@@ -8,9 +8,68 @@ use crate::app_state::AppState;
 pub async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     let mut metrics = String::new();
     let users = state.users.lock();
-    let connected_users = users.iter().filter(|x| x.1.connected).count();
     let blacklisted_users = users.iter().filter(|x| x.1.irc_blacklisted).count();
-    metrics.push_str(&prometheus_stat("Connected users", "connected_users", connected_users));
+    metrics.push_str(&prometheus_stat(
+        "Connected users",
+        "connected_users",
+        state.presence.online_count(),
+    ));
+    metrics.push_str(&prometheus_stat(
+        "Connected sessions, users with multiple clients open count more than once",
+        "connected_sessions",
+        state.presence.session_count(),
+    ));
+    metrics.push_str(&prometheus_stat(
+        "Disconnected sessions that can still be resumed",
+        "parked_sessions",
+        state.resumable.parked_count(),
+    ));
+    metrics.push_str(&prometheus_stat(
+        "Sessions closed because they stopped answering pings",
+        "reaped_sessions_heartbeat",
+        state.counters.reaped_heartbeat.load(Ordering::Relaxed),
+    ));
+    metrics.push_str(&prometheus_stat(
+        "Sessions closed because they were idle for too long",
+        "reaped_sessions_idle",
+        state.counters.reaped_idle.load(Ordering::Relaxed),
+    ));
+    metrics.push_str(&prometheus_stat(
+        "Sessions closed because they didn't read their messages fast enough",
+        "reaped_sessions_slow",
+        state.counters.reaped_slow.load(Ordering::Relaxed),
+    ));
+    metrics.push_str(&prometheus_stat(
+        "Sessions closed because they kept going over the rate limits",
+        "reaped_sessions_rate_limited",
+        state.counters.reaped_rate_limited.load(Ordering::Relaxed),
+    ));
+    metrics.push_str(&prometheus_stat(
+        "Messages rejected by a rate limit",
+        "rate_limited_messages",
+        state.counters.rate_limited.load(Ordering::Relaxed),
+    ));
+    metrics.push_str(&prometheus_stat(
+        "Players muted for going over the rate limits",
+        "rate_limit_mutes",
+        state.counters.rate_limit_mutes.load(Ordering::Relaxed),
+    ));
+    metrics.push_str(&prometheus_stat(
+        "Messages waiting to be sent to clients",
+        "queued_messages",
+        state.sessions.queued(),
+    ));
+    metrics.push_str(&prometheus_stat(
+        "Messages dropped because a client's queue was full",
+        "dropped_messages",
+        state.sessions.dropped.load(Ordering::Relaxed),
+    ));
+    metrics.push_str(&prometheus_stat(
+        "Presence updates merged because a client's queue was full",
+        "coalesced_presence_updates",
+        state.sessions.coalesced.load(Ordering::Relaxed),
+    ));
+    metrics.push_str(&prometheus_stat("Parties", "parties", state.parties.count()));
     metrics.push_str(&prometheus_stat(
         "Blocked users",
         "blocked_irc_users",
@@ -29,7 +88,7 @@ pub async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     metrics.push_str(&prometheus_stat(
         "Messages per second",
         "messages_per_second",
-        state.messages_sec.load(std::sync::atomic::Ordering::Relaxed),
+        state.messages_sec.load(Ordering::Relaxed),
     ));
     #[cfg(target_os = "linux")]
     add_process_stats(&mut metrics);
@@ -15,6 +15,7 @@ use crate::app_state::{AppState, User};
 pub mod broadcast;
 pub mod cosmetics;
 pub mod metrics;
+pub mod snapshots;
 pub mod users;
 
 #[derive(Deserialize)]
@@ -33,7 +34,12 @@ pub async fn load_admin(State(state): State<Arc<AppState>>, Query(query): Query<
 
     let (page, limit) = (query.page.unwrap_or(1) as usize, query.limit.unwrap_or(50) as usize);
 
-    let users = tmp.iter().skip((page - 1) * limit).take(limit).collect::<Vec<_>>();
+    let users = tmp
+        .iter()
+        .skip((page - 1) * limit)
+        .take(limit)
+        .map(|(uuid, user)| (*uuid, *user, state.presence.is_online(uuid)))
+        .collect::<Vec<_>>();
 
     Html(render_lazy(rsx! {
         head { {meta(users_len, cosmetics_len)} }
@@ -218,7 +224,7 @@ fn broadcast_field<'a, 'b>() -> LazyNodes<'a, 'b> {
     )
 }
 
-fn users_table<'a, 'b>(users: Vec<&'b (&Uuid, &User)>) -> LazyNodes<'a, 'b> {
+fn users_table<'a, 'b>(users: Vec<(&'b Uuid, &'b User, bool)>) -> LazyNodes<'a, 'b> {
     rsx!(
         table {
             tr {
@@ -230,7 +236,7 @@ fn users_table<'a, 'b>(users: Vec<&'b (&Uuid, &User)>) -> LazyNodes<'a, 'b> {
                 th { "Irc Blacklisted" }
                 th { "Flags" }
             }
-            users.iter().map(|(uuid,data)| {
+            users.iter().map(|(uuid, data, connected)| {
                 let prefix = serde_json::to_string(&data.enabled_prefix).unwrap();
                 let linked_discord = serde_json::to_string(&data.linked_discord).unwrap();
                 let flags = serde_json::to_string(&data.flags).unwrap();
@@ -241,7 +247,7 @@ fn users_table<'a, 'b>(users: Vec<&'b (&Uuid, &User)>) -> LazyNodes<'a, 'b> {
                         }
                         td { pre { "{uuid}" } }
                         td { pre { a { href: "#cos-{prefix}", "{prefix}" } } }
-                        td { pre { "{data.connected}" } }
+                        td { pre { "{connected}" } }
                         td { pre { "{linked_discord}" } }
                         td { pre { "{data.irc_blacklisted}" } }
                         td { pre { "{flags}" } }
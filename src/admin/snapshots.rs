@@ -0,0 +1,118 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::extract::{Json, Query, State};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    config::CONFIG,
+    error::Result,
+    messages::InternalMessages,
+    utils::{
+        retrieve_cosmetics::CosmeticFile,
+        snapshots::{list_snapshots, read_snapshot, write_snapshot, Snapshot},
+    },
+};
+
+#[derive(Deserialize)]
+pub struct SnapshotQuery {
+    pub name: String,
+}
+
+/// What restoring a snapshot would change, changed entries are `[live, snapshot]` pairs.
+#[derive(Default, Serialize)]
+pub struct SnapshotDiff {
+    pub users_added: HashMap<Uuid, Value>,
+    pub users_removed: HashMap<Uuid, Value>,
+    pub users_changed: HashMap<Uuid, [Value; 2]>,
+    pub cosmetics_added: HashMap<u8, Value>,
+    pub cosmetics_removed: HashMap<u8, Value>,
+    pub cosmetics_changed: HashMap<u8, [Value; 2]>,
+}
+
+pub async fn get_snapshots() -> Result<Json<Vec<Snapshot>>> {
+    Ok(Json(list_snapshots(&CONFIG.snapshot_dir)?))
+}
+
+pub async fn diff_snapshot(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SnapshotQuery>,
+) -> Result<Json<SnapshotDiff>> {
+    let snapshot = read_snapshot(&CONFIG.snapshot_dir, &query.name)?;
+    let live = state.cosmetic_file();
+    let mut diff = SnapshotDiff::default();
+
+    diff_maps(
+        user_values(&live)?,
+        user_values(&snapshot)?,
+        &mut diff.users_added,
+        &mut diff.users_removed,
+        &mut diff.users_changed,
+    );
+    diff_maps(
+        cosmetic_values(&live)?,
+        cosmetic_values(&snapshot)?,
+        &mut diff.cosmetics_added,
+        &mut diff.cosmetics_removed,
+        &mut diff.cosmetics_changed,
+    );
+    Ok(Json(diff))
+}
+
+fn user_values(file: &CosmeticFile) -> Result<HashMap<Uuid, Value>> {
+    let mut values = HashMap::new();
+    for (uuid, user) in &file.users {
+        values.insert(*uuid, serde_json::to_value(user)?);
+    }
+    Ok(values)
+}
+
+fn cosmetic_values(file: &CosmeticFile) -> Result<HashMap<u8, Value>> {
+    let mut values = HashMap::new();
+    for cosmetic in &file.cosmetics {
+        values.insert(cosmetic.id, serde_json::to_value(cosmetic)?);
+    }
+    Ok(values)
+}
+
+fn diff_maps<K: std::hash::Hash + Eq>(
+    mut live: HashMap<K, Value>,
+    snapshot: HashMap<K, Value>,
+    added: &mut HashMap<K, Value>,
+    removed: &mut HashMap<K, Value>,
+    changed: &mut HashMap<K, [Value; 2]>,
+) {
+    for (key, value) in snapshot {
+        match live.remove(&key) {
+            Some(current) if current == value => {}
+            Some(current) => {
+                changed.insert(key, [current, value]);
+            }
+            None => {
+                added.insert(key, value);
+            }
+        }
+    }
+    removed.extend(live);
+}
+
+pub async fn restore_snapshot(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SnapshotQuery>,
+) -> Result<&'static str> {
+    let snapshot = read_snapshot(&CONFIG.snapshot_dir, &query.name)?;
+
+    // Keep the state we are about to throw away so the restore can be undone, unless snapshots are disabled
+    if CONFIG.snapshot_count > 0 {
+        write_snapshot(&CONFIG.snapshot_dir, CONFIG.snapshot_count + 1, &state.cosmetic_file())?;
+    }
+
+    state.replace_cosmetics(snapshot);
+    state.save()?;
+    tracing::info!("Restored snapshot {}", query.name);
+
+    state.broadcast(InternalMessages::CosmeticsAck);
+    Ok("Ok")
+}
@@ -1,6 +1,9 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use axum::extract::{Json, Query, State};
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+};
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use serde::Deserialize;
 use serenity::model::prelude::UserId;
@@ -9,7 +12,9 @@ use uuid::Uuid;
 use crate::{
     app_state::{AppState, User},
     bitflags::CosmeticFlags,
+    cosmetic_log::CosmeticChange,
     error::Result,
+    messages::close_code,
     utils::{uuid_to_username, UuidAndUsername},
 };
 
@@ -27,6 +32,20 @@ pub struct DeleteUser {
     pub uuid: Uuid,
 }
 
+#[derive(Deserialize)]
+pub struct KickUser {
+    pub uuid: Uuid,
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct BanUser {
+    pub uuid: Uuid,
+    pub reason: Option<String>,
+    /// Seconds, the ban is permanent without it
+    pub duration: Option<u64>,
+}
+
 pub async fn uuids_to_usernames(Json(uuids): Json<Vec<Uuid>>) -> Json<Vec<UuidAndUsername>> {
     let results = FuturesUnordered::new();
     for uuid in uuids {
@@ -50,20 +69,59 @@ pub async fn get_users(State(state): State<Arc<AppState>>) -> Json<HashMap<Uuid,
 pub async fn add_user(State(state): State<Arc<AppState>>, Json(data): Json<AddUser>) -> &'static str {
     let mut users = state.users.lock();
     let def = users.get(&data.uuid).cloned().unwrap_or_default();
-    users.insert(
-        data.uuid,
-        User {
-            linked_discord: data.linked_discord.or(def.linked_discord),
-            enabled_prefix: data.enabled_prefix.or(def.enabled_prefix),
-            irc_blacklisted: data.irc_blacklisted.unwrap_or(def.irc_blacklisted),
-            flags: data.flags.unwrap_or(def.flags),
-            ..def
-        },
-    );
+    let def_prefix = def.enabled_prefix;
+    let user = User {
+        linked_discord: data.linked_discord.or(def.linked_discord),
+        enabled_prefix: data.enabled_prefix.or(def.enabled_prefix),
+        irc_blacklisted: data.irc_blacklisted.unwrap_or(def.irc_blacklisted),
+        flags: data.flags.unwrap_or(def.flags),
+        ..def
+    };
+    state.persist_user(data.uuid, &user);
+    if user.enabled_prefix != def_prefix {
+        state.record_cosmetic_change(CosmeticChange::Equipped {
+            uuid: data.uuid,
+            cosmetic_id: user.enabled_prefix,
+        });
+    }
+    users.insert(data.uuid, user);
     "ok"
 }
 pub async fn remove_user(State(state): State<Arc<AppState>>, Query(data): Query<DeleteUser>) -> &'static str {
     let mut users = state.users.lock();
-    users.remove(&data.uuid);
+    let removed = users.remove(&data.uuid);
+    state.forget_user(data.uuid);
+    if matches!(removed, Some(u) if u.enabled_prefix.is_some()) {
+        state.record_cosmetic_change(CosmeticChange::Equipped {
+            uuid: data.uuid,
+            cosmetic_id: None,
+        });
+    }
     "ok"
 }
+
+pub async fn kick_user(State(state): State<Arc<AppState>>, Json(data): Json<KickUser>) -> &'static str {
+    let reason = data.reason.unwrap_or_else(|| "Kicked by an admin".to_owned());
+    state.kick(data.uuid, close_code::KICKED, reason);
+    "ok"
+}
+
+pub async fn ban_user(
+    State(state): State<Arc<AppState>>,
+    Json(data): Json<BanUser>,
+) -> std::result::Result<&'static str, (StatusCode, &'static str)> {
+    if data.duration == Some(0) {
+        return Err((StatusCode::BAD_REQUEST, "A ban has to last at least a second"));
+    }
+    let reason = data.reason.unwrap_or_else(|| "Banned by an admin".to_owned());
+    state.ban(data.uuid, reason, data.duration.map(Duration::from_secs));
+    Ok("ok")
+}
+
+pub async fn unban_user(State(state): State<Arc<AppState>>, Query(data): Query<DeleteUser>) -> &'static str {
+    if state.unban(data.uuid) {
+        "ok"
+    } else {
+        "not banned"
+    }
+}
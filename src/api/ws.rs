@@ -1,174 +1,447 @@
 use std::{
+    collections::{HashSet, VecDeque},
     num::NonZeroU32,
-    sync::{atomic::Ordering, Arc},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
+        ws::{CloseFrame, Message, WebSocket},
         State, WebSocketUpgrade,
     },
-    response::IntoResponse,
+    http::StatusCode,
+    response::{IntoResponse, Response},
 };
 use futures_util::{SinkExt, StreamExt};
-use governor::{Quota, RateLimiter};
+use governor::{
+    clock::{Clock, DefaultClock, QuantaInstant},
+    NotUntil, Quota, RateLimiter,
+};
+use parking_lot::Mutex;
+use tokio::{
+    sync::oneshot,
+    time::{interval, sleep_until, MissedTickBehavior},
+};
 use uuid::Uuid;
 
 use crate::{
     app_state::AppState,
     config::CONFIG,
-    messages::{parse_ws_message, to_ws_message, InternalMessages, Messages},
+    cosmetic_log::CosmeticChange,
+    friends,
+    messages::{
+        close_code, parse_ws_message,
+        protocol::{
+            negotiate, Capability, Connected, Limits, DM_MESSAGES_PER_MINUTE, IRC_MESSAGES_PER_MINUTE,
+            PROTOCOL_VERSION, STATUS_FIELD_LENGTH,
+        },
+        to_ws_message, DmStatus, ErrorCode, InternalMessages, Messages, Rejection,
+    },
+    party::Party,
+    penalties::{Penalty, Violations},
+    sessions::{Outbox, Outgoing, ResumableSessions, Session},
     utils::{sanitize::sanitize_message, validate_session, Influx},
     Result,
 };
 
-pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> impl IntoResponse {
+pub async fn ws_handler(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> Response {
+    if state.is_shutting_down() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
     ws.on_upgrade(|socket| async move {
-        if let Err(e) = handle_socket(socket, state).await {
+        state.connections.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = handle_socket(socket, state.clone()).await {
             tracing::error!("Error handling socket: {:?}", e);
         };
+        state.connections.fetch_sub(1, Ordering::SeqCst);
     })
 }
 
+/// Liveness of a connection, updated by the receiving task and checked by the sending one.
+struct Liveness {
+    missed_pongs: AtomicUsize,
+    last_message: Mutex<Instant>,
+}
+
+impl Liveness {
+    fn new() -> Self {
+        Self {
+            missed_pongs: AtomicUsize::new(0),
+            last_message: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+/// Applies a change to the friends of two users, persists both and tells them about it.
+fn update_friends(
+    state: &AppState,
+    outbox: &Outbox,
+    uuid: Uuid,
+    other: Uuid,
+    nonce: Option<String>,
+    update: impl FnOnce(&mut friends::Users) -> std::result::Result<(), Rejection>,
+) {
+    let mut users = state.users.lock();
+    if let Err(error) = update(&mut users) {
+        state
+            .sessions
+            .reply(outbox, InternalMessages::user_error(uuid, error, nonce));
+        return;
+    }
+    for user_id in [uuid, other] {
+        if let Some(user) = users.get(&user_id) {
+            state.persist_user(user_id, user);
+        }
+    }
+    state.sessions.send(
+        uuid,
+        InternalMessages::FriendsUpdated {
+            requester_id: uuid,
+            uuid: other,
+            status: friends::status(&users, uuid, other),
+            nonce,
+        },
+    );
+    state.sessions.send(
+        other,
+        InternalMessages::FriendsUpdated {
+            requester_id: other,
+            uuid,
+            status: friends::status(&users, other, uuid),
+            nonce: None,
+        },
+    );
+}
+
+/// Sends the result of a party change, the party after the change and a player that was removed from it.
+fn update_party(
+    state: &AppState,
+    outbox: &Outbox,
+    uuid: Uuid,
+    nonce: Option<String>,
+    result: std::result::Result<(Option<Party>, Option<Uuid>), Rejection>,
+) {
+    match result {
+        Ok((party, removed)) => state.send_party_update(uuid, party, removed, nonce),
+        Err(error) => {
+            state
+                .sessions
+                .reply(outbox, InternalMessages::user_error(uuid, error, nonce));
+        }
+    }
+}
+
+/// Rejects a message that went over a rate limit and escalates when the client keeps doing that.
+fn rate_limited(
+    state: &AppState,
+    outbox: &Outbox,
+    violations: &mut Violations,
+    uuid: Uuid,
+    not_until: NotUntil<QuantaInstant>,
+    nonce: Option<String>,
+) {
+    state.counters.rate_limited.fetch_add(1, Ordering::Relaxed);
+    let now = Instant::now();
+    let (code, error, retry_after) = match violations.record(now) {
+        Penalty::Reject => (
+            ErrorCode::RateLimited,
+            "You are sending messages too fast",
+            not_until.wait_time_from(DefaultClock::default().now()),
+        ),
+        Penalty::Mute => {
+            tracing::debug!("{} was muted for going over the rate limits", uuid);
+            let duration = Duration::from_secs(CONFIG.ratelimit_mute_duration);
+            state.mutes.mute(uuid, now + duration);
+            state.counters.rate_limit_mutes.fetch_add(1, Ordering::Relaxed);
+            (
+                ErrorCode::Muted,
+                "You were muted for sending messages too fast",
+                duration,
+            )
+        }
+        Penalty::Disconnect => {
+            tracing::debug!("{} kept going over the rate limits", uuid);
+            state.counters.reaped_rate_limited.fetch_add(1, Ordering::Relaxed);
+            // Only this connection, the send task closes it
+            outbox.push(InternalMessages::Kick {
+                uuid,
+                code: close_code::RATE_LIMITED,
+                reason: "Sending messages too fast".to_owned(),
+            });
+            return;
+        }
+    };
+    state.sessions.reply(
+        outbox,
+        InternalMessages::UserError {
+            requester_id: uuid,
+            code,
+            error: error.to_owned(),
+            details: None,
+            retry_after: Some(retry_after.as_millis() as u64),
+            nonce,
+        },
+    );
+}
+
+/// Tells a muted player how long they can't chat for, returns false if they aren't muted.
+fn muted(state: &AppState, outbox: &Outbox, uuid: Uuid, nonce: Option<String>) -> bool {
+    let remaining = match state.mutes.remaining(&uuid, Instant::now()) {
+        Some(remaining) => remaining,
+        None => return false,
+    };
+    state.sessions.reply(
+        outbox,
+        InternalMessages::UserError {
+            requester_id: uuid,
+            code: ErrorCode::Muted,
+            error: "You are muted".to_owned(),
+            details: None,
+            retry_after: Some(remaining.as_millis() as u64),
+            nonce,
+        },
+    );
+    true
+}
+
+/// Whether a session is in a channel and still has the flags it needs, flags can be taken away after joining.
+fn in_channel(state: &AppState, channels: &HashSet<String>, uuid: Uuid, channel: &str) -> bool {
+    let flags = state.users.lock().get(&uuid).map(|u| u.flags).unwrap_or_default();
+    channels.contains(channel) && matches!(state.irc.get(channel), Some(c) if c.allows(flags))
+}
+
+/// A close frame, reasons are cut off at the 123 bytes that fit in one.
+fn close(code: u16, reason: &str) -> Message {
+    let mut reason = reason.to_owned();
+    while reason.len() > 123 {
+        reason.pop();
+    }
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }))
+}
+
 async fn handle_socket(stream: WebSocket, state: Arc<AppState>) -> Result<()> {
     let start = Instant::now();
     let (mut sender, mut receiver) = stream.split();
-    let mut uuid: Option<Uuid> = None;
-    let mut name: Option<String> = None;
     let lim = RateLimiter::direct(CONFIG.ratelimit_per_minute);
-    let irclim =
-        RateLimiter::direct(Quota::per_minute(NonZeroU32::new(4).unwrap()).allow_burst(NonZeroU32::new(8).unwrap()));
+    let irclim = RateLimiter::direct(
+        Quota::per_minute(NonZeroU32::new(IRC_MESSAGES_PER_MINUTE).unwrap()).allow_burst(NonZeroU32::new(8).unwrap()),
+    );
+    let dmlim = RateLimiter::direct(Quota::per_minute(NonZeroU32::new(DM_MESSAGES_PER_MINUTE).unwrap()));
+    let mut connected = None;
+    let mut resumed = None;
 
-    while let Some(Ok(message)) = receiver.next().await {
+    while let Some(Ok(message)) = tokio::select! {
+        message = receiver.next() => message,
+        _ = state.shutting_down() => None,
+    } {
         if let Message::Text(txt) = message {
             tracing::info!("{:?}", parse_ws_message(&txt));
-            if let Some(Messages::Connect { server_id, username }) = parse_ws_message(&txt) {
-                let data = validate_session(server_id, username).await?;
-                uuid = Some(data.id);
-                name = Some(data.name);
-                break;
+            match parse_ws_message(&txt) {
+                Some(Messages::Connect {
+                    server_id,
+                    username,
+                    codec,
+                    protocol,
+                    mod_name,
+                    mod_version,
+                    capabilities,
+                }) => {
+                    let data = validate_session(server_id, username).await?;
+                    let ban = state
+                        .users
+                        .lock()
+                        .get(&data.id)
+                        .and_then(|u| u.active_ban().map(|b| b.reason.clone()));
+                    if let Some(reason) = ban {
+                        tracing::debug!("{} is banned", data.id);
+                        let _ = sender.send(close(close_code::BANNED, &reason)).await;
+                        return Ok(());
+                    }
+                    let features = match protocol {
+                        Some(protocol) => {
+                            tracing::debug!(
+                                "{} connected with protocol {} from {} {}",
+                                data.id,
+                                protocol,
+                                mod_name.as_deref().unwrap_or("unknown"),
+                                mod_version.as_deref().unwrap_or("unknown")
+                            );
+                            Some(negotiate(&capabilities))
+                        }
+                        None => None,
+                    };
+                    connected = Some((data.id, codec, features));
+                    break;
+                }
+                Some(Messages::Resume { token }) => match state.resumable.resume(&token).await {
+                    Some(parked) => {
+                        resumed = Some(parked);
+                        break;
+                    }
+                    None => {
+                        let error = Messages::Error {
+                            code: ErrorCode::InvalidResumeToken,
+                            error: "Invalid or expired resume token".to_owned(),
+                            details: None,
+                            retry_after: None,
+                            nonce: None,
+                        };
+                        let _ = sender.send(to_ws_message(error)).await;
+                    }
+                },
+                _ => {}
             }
         }
     }
 
-    if let Err(e) = sender.send(to_ws_message(Messages::ConnectedResponse(true))).await {
-        tracing::error!("Error sending message: {}", e);
-        return Ok(());
-    }
-    let uuid = match uuid {
-        Some(uuid) => uuid,
-        None => return Ok(()),
-    };
-    let _name = match name {
-        Some(name) => name,
-        None => return Ok(()),
+    let (session, outbox, missed) = match (connected, resumed) {
+        (Some((uuid, codec, features)), _) => {
+            // Subscribe before sending joined message.
+            let legacy = features.is_none();
+            let session = Session::new(state.sessions.new_id(), uuid, codec, features.unwrap_or_default());
+            let outbox = state.sessions.register(&session);
+            let connected = if legacy {
+                Connected::Legacy(true)
+            } else {
+                Connected::Negotiated {
+                    version: PROTOCOL_VERSION,
+                    features: session.features.clone(),
+                    limits: Limits::current(),
+                    resume_token: session.token.clone(),
+                }
+            };
+            if let Err(e) = sender.send(to_ws_message(Messages::ConnectedResponse(connected))).await {
+                tracing::error!("Error sending message: {}", e);
+                state.sessions.unregister(&session);
+                return Ok(());
+            }
+            state.player_connected(uuid);
+            (session, outbox, VecDeque::new())
+        }
+        (None, Some(resumed)) => {
+            let mut session = resumed.session;
+            session.token = session.new_token();
+            let msg = Messages::Resumed {
+                resume_token: session.token.clone(),
+                missed: resumed.missed.len(),
+                complete: resumed.complete,
+            };
+            if let Err(e) = sender.send(to_ws_message(msg)).await {
+                tracing::error!("Error sending message: {}", e);
+                state.player_disconnected(&session);
+                return Ok(());
+            }
+            (session, resumed.outbox, resumed.missed)
+        }
+        (None, None) => return Ok(()),
     };
+    let Session { uuid, codec, .. } = session;
 
     tokio::spawn(Influx::new("connect").label("user_id", &uuid.to_string()).send());
 
-    // Subscribe before sending joined message.
-    let mut rx = state.tx.subscribe();
-
-    // This task will receive broadcast messages and send text message to our client.
+    // This task will receive messages for this session and send them to our client, it hands the outbox back
+    // when stopped so the session can be parked.
+    let (stop, mut stopped) = oneshot::channel::<()>();
+    let liveness = Arc::new(Liveness::new());
+    let state_clone = state.clone();
+    let liveness_clone = liveness.clone();
+    let own_outbox = outbox.clone();
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            match msg {
-                InternalMessages::UserInvalidJson { requester_id, error } => {
-                    if requester_id == uuid {
-                        let _ = sender.send(to_ws_message(Messages::Error { error, nonce: None })).await;
-                    }
-                }
-                InternalMessages::UserError {
-                    requester_id,
-                    error,
-                    nonce,
-                } => {
-                    if requester_id == uuid {
-                        let _ = sender.send(to_ws_message(Messages::Error { error, nonce })).await;
-                    }
+        let state = state_clone;
+        let liveness = liveness_clone;
+        for msg in missed {
+            let _ = sender.send(codec.encode(msg)).await;
+        }
+        let mut heartbeat = interval(Duration::from_secs(CONFIG.heartbeat_interval.max(1)));
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        heartbeat.tick().await;
+        let idle_timeout = Duration::from_secs(CONFIG.idle_timeout);
+        loop {
+            let idle_deadline = *liveness.last_message.lock() + idle_timeout;
+            let msg = tokio::select! {
+                msg = outbox.recv() => msg,
+                _ = &mut stopped => return Some(outbox),
+                _ = state.shutting_down() => {
+                    let _ = sender.send(close(close_code::GOING_AWAY, "Server shutting down")).await;
+                    return None;
                 }
-                InternalMessages::CosmeticsUpdate {
-                    requester_id,
-                    cosmetic_id,
-                    nonce,
-                } => {
-                    if requester_id == uuid {
-                        let _ = sender
-                            .send(to_ws_message(Messages::CosmeticsUpdated { cosmetic_id, nonce }))
-                            .await;
-                    } else {
-                        let _ = sender.send(to_ws_message(Messages::CosmeticAck)).await;
+                _ = heartbeat.tick(), if CONFIG.heartbeat_interval > 0 => {
+                    if liveness.missed_pongs.fetch_add(1, Ordering::SeqCst) >= CONFIG.heartbeat_missed_pongs {
+                        tracing::debug!("{} stopped answering pings", uuid);
+                        state.counters.reaped_heartbeat.fetch_add(1, Ordering::Relaxed);
+                        let _ = sender.send(close(close_code::HEARTBEAT_TIMEOUT, "Missed too many pings")).await;
+                        // Most likely a dropped connection, keep the session around so it can be resumed
+                        return Some(outbox);
                     }
+                    let _ = sender.send(Message::Ping(Vec::new())).await;
+                    continue;
                 }
-                InternalMessages::UserRequestResponse {
-                    is_online,
-                    requester_id,
-                    user_id,
-                    nonce,
-                } => {
-                    if requester_id != uuid {
+                _ = sleep_until(idle_deadline.into()), if CONFIG.idle_timeout > 0 => {
+                    if liveness.last_message.lock().elapsed() < idle_timeout {
                         continue;
                     }
-                    let msg = Messages::IsOnlineResponse {
-                        is_online,
-                        uuid: user_id,
-                        nonce,
-                    };
-                    let _ = sender.send(to_ws_message(msg)).await;
+                    tracing::debug!("{} was idle for too long", uuid);
+                    state.counters.reaped_idle.fetch_add(1, Ordering::Relaxed);
+                    let _ = sender.send(close(close_code::IDLE_TIMEOUT, "Idle for too long")).await;
+                    return None;
                 }
-                InternalMessages::UserRequestBulkResponse {
-                    requester_id,
-                    users,
-                    nonce,
-                } => {
-                    if requester_id != uuid {
-                        continue;
-                    }
-                    let msg = Messages::IsOnlineBulkResponse { users, nonce };
-                    let _ = sender.send(to_ws_message(msg)).await;
+            };
+            match msg {
+                Some(Outgoing::Message(msg)) => {
+                    let _ = sender.send(codec.encode(msg)).await;
                 }
-                InternalMessages::BroadCastMessage { message, to } => {
-                    if to.contains(&uuid) || to.is_empty() {
-                        let msg = Messages::Broadcast(message);
-                        let _ = sender.send(to_ws_message(msg)).await;
-                    }
+                Some(Outgoing::Close { code, reason }) => {
+                    tracing::debug!("{} was kicked: {}", uuid, reason);
+                    let _ = sender.send(close(code, &reason)).await;
+                    return None;
                 }
-                InternalMessages::Pong {
-                    nonce,
-                    uuid: requester_id,
-                } => {
-                    if requester_id != uuid {
-                        continue;
-                    }
-                    let _ = sender.send(to_ws_message(Messages::Pong(nonce))).await;
-                }
-                InternalMessages::IrcCreate {
-                    message,
-                    sender: user,
-                    date,
-                } => {
-                    let msg = Messages::IrcCreated {
-                        message,
-                        date,
-                        sender: user,
-                    };
-                    let _ = sender.send(to_ws_message(msg)).await;
+                None => {
+                    tracing::debug!("{} didn't keep up with its messages", uuid);
+                    state.counters.reaped_slow.fetch_add(1, Ordering::Relaxed);
+                    let _ = sender.send(close(close_code::TOO_SLOW, "Too slow")).await;
+                    return None;
                 }
-                _ => {}
             }
         }
     });
 
     // Clone things we want to pass to the receiving task.
-    let tx = state.tx.clone();
-
     // This task will receive messages from client and send them to broadcast subscribers.
     let state_clone = state.clone();
+    let features = session.features.clone();
+    let subscriptions = session.subscriptions.clone();
+    let channels = session.channels.clone();
+    let session_clone = session.clone();
     let mut recv_task = tokio::spawn(async move {
         let state = state_clone;
+        let session = session_clone;
+        let outbox = own_outbox;
+        let mut violations = Violations::new(
+            Duration::from_secs(CONFIG.ratelimit_violation_window),
+            CONFIG.ratelimit_mute_after,
+            CONFIG.ratelimit_disconnect_after,
+        );
 
-        while let Some(Ok(Message::Text(text))) = receiver.next().await {
+        while let Some(Ok(message)) = receiver.next().await {
+            let msg = match message {
+                Message::Text(_) | Message::Binary(_) => codec.decode(&message),
+                Message::Pong(_) => {
+                    liveness.missed_pongs.store(0, Ordering::SeqCst);
+                    continue;
+                }
+                // Pings are answered by axum
+                Message::Ping(_) => continue,
+                // Closed on purpose, so there is nothing to resume
+                Message::Close(_) => return true,
+            };
+            *liveness.last_message.lock() = Instant::now();
             tracing::debug!("Add message/s: {}", state.messages_sec.fetch_add(1, Ordering::SeqCst));
             let state_clone = state.clone();
             tokio::spawn(async move {
@@ -180,40 +453,102 @@ async fn handle_socket(stream: WebSocket, state: Arc<AppState>) -> Result<()> {
             });
 
             if let Err(e) = lim.check() {
-                tracing::error!("Rate limit exceeded: {}", e);
+                let nonce = msg.as_ref().and_then(Messages::nonce);
+                rate_limited(&state, &outbox, &mut violations, uuid, e, nonce);
                 continue;
             }
-            let msg = parse_ws_message(&text);
-            tracing::debug!("{uuid} {}", text);
+            tracing::debug!("{uuid} {:?}", msg);
+            if let Some(capability) = msg.as_ref().and_then(Messages::capability) {
+                if !features.contains(&capability) {
+                    state.sessions.reply(
+                        &outbox,
+                        InternalMessages::UserError {
+                            requester_id: uuid,
+                            code: ErrorCode::CapabilityNotNegotiated,
+                            error: "This capability was not negotiated".to_owned(),
+                            details: serde_json::to_value(capability)
+                                .ok()
+                                .and_then(|c| c.as_str().map(ToOwned::to_owned)),
+                            retry_after: None,
+                            nonce: msg.as_ref().and_then(Messages::nonce),
+                        },
+                    );
+                    continue;
+                }
+            }
             match msg {
                 Some(Messages::Connect { .. }) => {
-                    let _ = tx.send(InternalMessages::UserInvalidJson {
-                        requester_id: uuid,
-                        error: "Already connected".to_owned(),
-                    });
+                    let error = (ErrorCode::AlreadyConnected, "Already connected");
+                    state
+                        .sessions
+                        .reply(&outbox, InternalMessages::user_error(uuid, error, None));
                 }
-                Some(Messages::Error { error, .. }) => {
-                    let _ = tx.send(InternalMessages::UserInvalidJson {
-                        requester_id: uuid,
-                        error,
-                    });
+                Some(Messages::Error {
+                    code,
+                    error,
+                    details,
+                    retry_after,
+                    nonce,
+                }) => {
+                    state.sessions.reply(
+                        &outbox,
+                        InternalMessages::UserError {
+                            requester_id: uuid,
+                            code,
+                            error,
+                            details,
+                            retry_after,
+                            nonce,
+                        },
+                    );
                 }
                 Some(Messages::IsOnline { uuid: user_id, nonce }) => {
-                    let _ = tx.send(InternalMessages::RequestUser {
-                        user_id,
+                    tokio::spawn(
+                        Influx::new("request_online")
+                            .label("uuid", &uuid.to_string())
+                            .value("user", &user_id.to_string())
+                            .send(),
+                    );
+                    let status = if features.contains(&Capability::Status) {
+                        state.visible_status(uuid, user_id)
+                    } else {
+                        None
+                    };
+                    let msg = InternalMessages::UserRequestResponse {
+                        is_online: state.presence.is_online(&user_id),
                         requester_id: uuid,
+                        user_id,
+                        status,
                         nonce,
-                    });
+                    };
+                    state.sessions.reply(&outbox, msg);
                 }
                 Some(Messages::IsOnlineBulk { uuids, nonce }) => {
-                    let _ = tx.send(InternalMessages::RequestUsersBulk {
-                        user_ids: uuids,
+                    tokio::spawn(
+                        Influx::new("bulk_request_online")
+                            .label("uuid", &uuid.to_string())
+                            .send(),
+                    );
+                    let statuses = features.contains(&Capability::Status).then(|| {
+                        uuids
+                            .iter()
+                            .filter_map(|user_id| Some((*user_id, state.visible_status(uuid, *user_id)?)))
+                            .collect()
+                    });
+                    let users = uuids
+                        .into_iter()
+                        .map(|user_id| (user_id, state.presence.is_online(&user_id)))
+                        .collect();
+                    let msg = InternalMessages::UserRequestBulkResponse {
                         requester_id: uuid,
+                        users,
+                        statuses,
                         nonce,
-                    });
+                    };
+                    state.sessions.reply(&outbox, msg);
                 }
                 Some(Messages::Ping(nonce)) => {
-                    let _ = tx.send(InternalMessages::Pong { nonce, uuid });
+                    state.sessions.reply(&outbox, InternalMessages::Pong { nonce, uuid });
                 }
                 Some(Messages::CosmeticsUpdate { cosmetic_id, nonce }) => {
                     let mut users = state.users.lock();
@@ -221,11 +556,10 @@ async fn handle_socket(stream: WebSocket, state: Arc<AppState>) -> Result<()> {
                     let mut user = match user {
                         Some(user) => user.clone(),
                         None => {
-                            let _ = tx.send(InternalMessages::UserError {
-                                requester_id: uuid,
-                                error: "You dont have any cosmetcs".to_owned(),
-                                nonce,
-                            });
+                            let error = (ErrorCode::Forbidden, "You dont have any cosmetcs");
+                            state
+                                .sessions
+                                .reply(&outbox, InternalMessages::user_error(uuid, error, nonce));
                             continue;
                         }
                     };
@@ -235,35 +569,307 @@ async fn handle_socket(stream: WebSocket, state: Arc<AppState>) -> Result<()> {
                         let cosmetic = match cosmetic {
                             Some(cosmetic) => cosmetic,
                             None => {
-                                let _ = tx.send(InternalMessages::UserError {
-                                    requester_id: uuid,
-                                    error: "Cosmetic not found".to_owned(),
-                                    nonce,
-                                });
+                                let error = (ErrorCode::NotFound, "Cosmetic not found");
+                                state
+                                    .sessions
+                                    .reply(&outbox, InternalMessages::user_error(uuid, error, nonce));
                                 continue;
                             }
                         };
                         if !user.flags.contains(cosmetic.required_flags) {
-                            let _ = tx.send(InternalMessages::UserError {
-                                requester_id: uuid,
-                                error: "You dont have this cosmetics".to_owned(),
-                                nonce,
-                            });
+                            let error = (ErrorCode::Forbidden, "You dont have this cosmetics");
+                            state
+                                .sessions
+                                .reply(&outbox, InternalMessages::user_error(uuid, error, nonce));
                             continue;
                         }
                         Some(cosmetic_id)
                     } else {
                         None
                     };
+                    state.persist_user(uuid, &user);
                     users.insert(uuid, user);
+                    state.record_cosmetic_change(CosmeticChange::Equipped { uuid, cosmetic_id });
 
-                    let _ = tx.send(InternalMessages::CosmeticsUpdate {
+                    state.broadcast(InternalMessages::CosmeticsUpdate {
                         cosmetic_id,
                         nonce,
                         requester_id: uuid,
                     });
                 }
-                Some(Messages::IrcCreate { message }) => {
+                Some(Messages::CosmeticsSync { since, nonce }) => {
+                    let changes = since.and_then(|since| state.cosmetic_log.since(since));
+                    let msg = match changes {
+                        Some(changes) => InternalMessages::CosmeticsSync {
+                            requester_id: uuid,
+                            version: changes.last().map_or(since.unwrap_or_default(), |d| d.version),
+                            cosmetics: None,
+                            users: None,
+                            changes: Some(changes),
+                            nonce,
+                        },
+                        None => {
+                            let (version, cosmetics, users) = state.cosmetics_snapshot();
+                            InternalMessages::CosmeticsSync {
+                                requester_id: uuid,
+                                version,
+                                cosmetics: Some(cosmetics),
+                                users: Some(users),
+                                changes: None,
+                                nonce,
+                            }
+                        }
+                    };
+                    state.sessions.reply(&outbox, msg);
+                }
+                Some(Messages::IrcCreate { message, channel }) => {
+                    let blacklisted = state
+                        .users
+                        .lock()
+                        .get(&uuid)
+                        .map(|u| u.irc_blacklisted)
+                        .unwrap_or_default();
+                    if blacklisted {
+                        continue;
+                    }
+
+                    if !in_channel(&state, &channels.lock(), uuid, &channel) {
+                        state.sessions.reply(
+                            &outbox,
+                            InternalMessages::UserError {
+                                requester_id: uuid,
+                                code: ErrorCode::NotMember,
+                                error: format!("You are not in {}", channel),
+                                details: Some(channel),
+                                retry_after: None,
+                                nonce: None,
+                            },
+                        );
+                        continue;
+                    }
+
+                    if muted(&state, &outbox, uuid, None) {
+                        continue;
+                    }
+                    if let Err(e) = irclim.check() {
+                        rate_limited(&state, &outbox, &mut violations, uuid, e, None);
+                        continue;
+                    }
+
+                    state.send_irc(channel, uuid, sanitize_message(&message));
+                }
+                Some(Messages::IrcHistory {
+                    channel,
+                    before,
+                    limit,
+                    nonce,
+                }) => {
+                    if !in_channel(&state, &channels.lock(), uuid, &channel) {
+                        state.sessions.reply(
+                            &outbox,
+                            InternalMessages::UserError {
+                                requester_id: uuid,
+                                code: ErrorCode::NotMember,
+                                error: format!("You are not in {}", channel),
+                                details: Some(channel),
+                                retry_after: None,
+                                nonce,
+                            },
+                        );
+                        continue;
+                    }
+                    let messages = state.irc_history.page(&channel, before, limit.unwrap_or(50));
+                    state.sessions.reply(
+                        &outbox,
+                        InternalMessages::IrcHistory {
+                            requester_id: uuid,
+                            channel,
+                            messages,
+                            nonce,
+                        },
+                    );
+                }
+                Some(Messages::IrcJoin { channel, nonce }) => {
+                    let flags = state.users.lock().get(&uuid).map(|u| u.flags).unwrap_or_default();
+                    let error = match state.irc.get(&channel) {
+                        None => Some((ErrorCode::NotFound, format!("Channel {} does not exist", channel))),
+                        Some(c) if !c.allows(flags) => {
+                            Some((ErrorCode::Forbidden, format!("You can't join {}", channel)))
+                        }
+                        Some(_) => None,
+                    };
+                    if let Some((code, error)) = error {
+                        state.sessions.reply(
+                            &outbox,
+                            InternalMessages::UserError {
+                                requester_id: uuid,
+                                code,
+                                error,
+                                details: Some(channel),
+                                retry_after: None,
+                                nonce,
+                            },
+                        );
+                        continue;
+                    }
+                    channels.lock().insert(channel.clone());
+                    state.sessions.reply(
+                        &outbox,
+                        InternalMessages::IrcMembership {
+                            requester_id: uuid,
+                            channel,
+                            joined: true,
+                            nonce,
+                        },
+                    );
+                }
+                Some(Messages::IrcLeave { channel, nonce }) => {
+                    channels.lock().remove(&channel);
+                    state.sessions.reply(
+                        &outbox,
+                        InternalMessages::IrcMembership {
+                            requester_id: uuid,
+                            channel,
+                            joined: false,
+                            nonce,
+                        },
+                    );
+                }
+                Some(Messages::DmSend { to, message, nonce }) => {
+                    let blacklisted = state
+                        .users
+                        .lock()
+                        .get(&uuid)
+                        .map(|u| u.irc_blacklisted)
+                        .unwrap_or_default();
+
+                    let status = if blacklisted {
+                        DmStatus::Rejected
+                    } else if muted(&state, &outbox, uuid, nonce.clone()) {
+                        continue;
+                    } else if let Err(e) = dmlim.check() {
+                        rate_limited(&state, &outbox, &mut violations, uuid, e, nonce);
+                        continue;
+                    } else {
+                        let msg = InternalMessages::Dm {
+                            message: sanitize_message(&message),
+                            sender: uuid,
+                            to,
+                            date: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+                        };
+                        // Also offline when none of their connections negotiated dms
+                        if state.sessions.send(to, msg) {
+                            DmStatus::Delivered
+                        } else {
+                            DmStatus::Offline
+                        }
+                    };
+                    state.sessions.reply(
+                        &outbox,
+                        InternalMessages::DmStatus {
+                            requester_id: uuid,
+                            to,
+                            status,
+                            nonce,
+                        },
+                    );
+                }
+                Some(Messages::FriendsRequest { uuid: other, nonce }) => {
+                    update_friends(&state, &outbox, uuid, other, nonce, |users| {
+                        friends::request(
+                            users,
+                            uuid,
+                            other,
+                            state.presence.is_online(&other),
+                            CONFIG.friend_limit,
+                        )
+                    });
+                }
+                Some(Messages::FriendsAccept { uuid: other, nonce }) => {
+                    update_friends(&state, &outbox, uuid, other, nonce, |users| {
+                        friends::accept(users, uuid, other, CONFIG.friend_limit)
+                    });
+                }
+                Some(Messages::FriendsDecline { uuid: other, nonce }) => {
+                    update_friends(&state, &outbox, uuid, other, nonce, |users| {
+                        friends::decline(users, uuid, other)
+                    });
+                }
+                Some(Messages::FriendsRemove { uuid: other, nonce }) => {
+                    update_friends(&state, &outbox, uuid, other, nonce, |users| {
+                        friends::remove(users, uuid, other)
+                    });
+                }
+                Some(Messages::FriendsList { nonce }) => {
+                    let (friends, requests) = state
+                        .users
+                        .lock()
+                        .get(&uuid)
+                        .map(|u| (u.friends.clone(), u.friend_requests.iter().copied().collect()))
+                        .unwrap_or_default();
+                    let friends = friends
+                        .into_iter()
+                        .map(|user_id| (user_id, state.presence.is_online(&user_id)))
+                        .collect();
+                    state.sessions.reply(
+                        &outbox,
+                        InternalMessages::FriendsList {
+                            requester_id: uuid,
+                            friends,
+                            requests,
+                            nonce,
+                        },
+                    );
+                }
+                Some(Messages::PartyCreate { nonce }) => {
+                    let result = state.parties.create(uuid).map(|party| (Some(party), None));
+                    update_party(&state, &outbox, uuid, nonce, result);
+                }
+                Some(Messages::PartyInvite { uuid: player, nonce }) => {
+                    match state.parties.invite(uuid, player, CONFIG.party_size) {
+                        Ok(party) => {
+                            let msg = InternalMessages::PartyInvited {
+                                to: player,
+                                party: party.id,
+                                from: uuid,
+                            };
+                            state.sessions.send(player, msg);
+                            state.sessions.send(
+                                uuid,
+                                InternalMessages::PartyUpdated {
+                                    to: vec![uuid],
+                                    requester_id: uuid,
+                                    party: Some(party),
+                                    nonce,
+                                },
+                            );
+                        }
+                        Err(error) => update_party(&state, &outbox, uuid, nonce, Err(error)),
+                    }
+                }
+                Some(Messages::PartyAccept { party, nonce }) => {
+                    let result = state
+                        .parties
+                        .accept(uuid, party, CONFIG.party_size)
+                        .map(|party| (Some(party), None));
+                    update_party(&state, &outbox, uuid, nonce, result);
+                }
+                Some(Messages::PartyLeave { nonce }) => {
+                    let result = state.parties.leave(uuid).map(|party| (party, Some(uuid)));
+                    update_party(&state, &outbox, uuid, nonce, result);
+                }
+                Some(Messages::PartyKick { uuid: player, nonce }) => {
+                    let result = state
+                        .parties
+                        .kick(uuid, player)
+                        .map(|party| (Some(party), Some(player)));
+                    update_party(&state, &outbox, uuid, nonce, result);
+                }
+                Some(Messages::PartyTransfer { uuid: player, nonce }) => {
+                    let result = state.parties.transfer(uuid, player).map(|party| (Some(party), None));
+                    update_party(&state, &outbox, uuid, nonce, result);
+                }
+                Some(Messages::PartyChat { message }) => {
                     let blacklisted = state
                         .users
                         .lock()
@@ -274,45 +880,160 @@ async fn handle_socket(stream: WebSocket, state: Arc<AppState>) -> Result<()> {
                         continue;
                     }
 
+                    let party = match state.parties.get(uuid) {
+                        Some(party) => party,
+                        None => {
+                            update_party(
+                                &state,
+                                &outbox,
+                                uuid,
+                                None,
+                                Err((ErrorCode::NotMember, "You are not in a party")),
+                            );
+                            continue;
+                        }
+                    };
+
+                    if muted(&state, &outbox, uuid, None) {
+                        continue;
+                    }
                     if let Err(e) = irclim.check() {
-                        tracing::error!("Rate limit exceeded: {}", e);
+                        rate_limited(&state, &outbox, &mut violations, uuid, e, None);
                         continue;
                     }
 
-                    let _ = tx.send(InternalMessages::IrcCreate {
+                    let msg = InternalMessages::PartyChat {
+                        to: party.members.clone(),
                         message: sanitize_message(&message),
                         sender: uuid,
-                        date: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
-                    });
+                        date: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+                    };
+                    state.sessions.send_many(&party.members, msg);
+                }
+                Some(Messages::StatusSet { status, privacy, nonce }) => {
+                    if matches!(&status, Some(s) if !s.is_valid()) {
+                        let error = InternalMessages::UserError {
+                            requester_id: uuid,
+                            code: ErrorCode::LimitReached,
+                            error: format!("Status fields can be at most {} characters", STATUS_FIELD_LENGTH),
+                            details: None,
+                            retry_after: None,
+                            nonce,
+                        };
+                        state.sessions.reply(&outbox, error);
+                        continue;
+                    }
+                    if let Some(status) = status {
+                        state.presence.set_status(uuid, status);
+                    }
+                    let privacy = {
+                        let mut users = state.users.lock();
+                        match privacy {
+                            Some(privacy) => {
+                                let user = users.entry(uuid).or_default();
+                                user.status_privacy = privacy;
+                                state.persist_user(uuid, user);
+                                privacy
+                            }
+                            None => users.get(&uuid).map(|u| u.status_privacy).unwrap_or_default(),
+                        }
+                    };
+                    let msg = InternalMessages::StatusUpdated {
+                        requester_id: uuid,
+                        status: state.presence.status(&uuid),
+                        privacy,
+                        nonce,
+                    };
+                    state.sessions.reply(&outbox, msg);
+                }
+                Some(Messages::PresenceSubscribe { uuids, nonce }) => {
+                    let mut subscriptions = subscriptions.lock();
+                    let new = uuids
+                        .iter()
+                        .filter(|u| !subscriptions.contains(u))
+                        .collect::<HashSet<_>>();
+                    if subscriptions.len() + new.len() > CONFIG.presence_subscription_limit {
+                        state.sessions.reply(
+                            &outbox,
+                            InternalMessages::UserError {
+                                requester_id: uuid,
+                                code: ErrorCode::LimitReached,
+                                error: format!(
+                                    "You can only subscribe to {} users",
+                                    CONFIG.presence_subscription_limit
+                                ),
+                                details: None,
+                                retry_after: None,
+                                nonce,
+                            },
+                        );
+                        continue;
+                    }
+                    subscriptions.extend(uuids.iter().copied());
+                    state.sessions.subscribe(&session, &uuids);
+                    let users = uuids
+                        .into_iter()
+                        .map(|user_id| (user_id, state.presence.is_online(&user_id)))
+                        .collect();
+                    state.sessions.reply(
+                        &outbox,
+                        InternalMessages::PresenceSubscribed {
+                            requester_id: uuid,
+                            users,
+                            nonce,
+                        },
+                    );
+                }
+                Some(Messages::PresenceUnsubscribe { uuids, nonce }) => {
+                    let mut subscriptions = subscriptions.lock();
+                    for user_id in &uuids {
+                        subscriptions.remove(user_id);
+                    }
+                    state.sessions.unsubscribe(session.id, &uuids);
+                    state.sessions.reply(
+                        &outbox,
+                        InternalMessages::PresenceUnsubscribed {
+                            requester_id: uuid,
+                            uuids,
+                            nonce,
+                        },
+                    );
                 }
                 _ => {}
             }
         }
+        false
     });
 
-    // If any one of the tasks exit, abort the other.
-    tokio::select! {
-        _ = (&mut send_task) => recv_task.abort(),
-        _ = (&mut recv_task) => send_task.abort(),
+    // If the send task exits abort the receiving one, if the connection drops get the receiver back to park it.
+    let outbox = tokio::select! {
+        outbox = (&mut send_task) => {
+            recv_task.abort();
+            outbox.ok().flatten()
+        }
+        closed = (&mut recv_task) => {
+            let _ = stop.send(());
+            let outbox = send_task.await.ok().flatten();
+            if closed.unwrap_or(false) {
+                None
+            } else {
+                outbox
+            }
+        }
     };
 
     tracing::debug!("{} disconnected from the website", uuid);
+    match (outbox, session.token.clone()) {
+        (Some(outbox), Some(token)) if !state.is_shutting_down() => {
+            ResumableSessions::park(&state, token, session, outbox)
+        }
+        _ => state.player_disconnected(&session),
+    }
+    tracing::info!("TOTAL: {}", state.presence.online_count());
     Influx::new("disconnect")
         .label("uuid", &uuid.to_string())
-        .value(
-            "duration",
-            &start.duration_since(Instant::now()).as_millis().to_string(),
-        )
+        .value("duration", &start.elapsed().as_millis().to_string())
         .await?;
-    let mut users = state.users.lock();
-    let user = users.get(&uuid);
-    let mut user = match user {
-        Some(user) => user.clone(),
-        None => return Ok(()),
-    };
-    user.connected = false;
-    users.insert(uuid, user);
-    tracing::info!("TOTAL: {}", users.iter().filter(|(_, u)| u.connected).count());
 
     Ok(())
 }
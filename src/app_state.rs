@@ -1,21 +1,306 @@
-use std::{collections::HashMap, sync::atomic::AtomicU16};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU16, AtomicUsize},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use serenity::model::prelude::UserId;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, watch};
 use uuid::Uuid;
 
-use crate::{bitflags::CosmeticFlags, messages::InternalMessages};
+use crate::{
+    bitflags::CosmeticFlags,
+    cosmetic_log::{CosmeticChange, CosmeticLog},
+    error::Result,
+    irc::{history::IrcHistory, IrcChannels},
+    messages::{close_code, InternalMessages},
+    party::{Parties, Party},
+    penalties::Mutes,
+    presence::{Presence, Status, StatusPrivacy},
+    sessions::{ResumableSessions, Session, SessionRegistry},
+    storage::Storage,
+    utils::retrieve_cosmetics::CosmeticFile,
+};
 
 pub struct AppState {
+    /// Irc messages, relayed to discord in the background by `handle_internal`
     pub tx: broadcast::Sender<InternalMessages>,
+    pub sessions: SessionRegistry,
+    /// Lock this before `cosmetics` when both are needed
     pub users: Mutex<HashMap<Uuid, User>>,
     pub cosmetics: Mutex<Vec<Cosmetic>>,
     pub messages_sec: AtomicU16,
+    pub storage: Box<dyn Storage>,
+    /// Open websocket connections, including ones that haven't connected to a player yet
+    pub connections: AtomicUsize,
+    pub presence: Presence,
+    pub resumable: ResumableSessions,
+    pub counters: Counters,
+    pub irc: IrcChannels,
+    pub irc_history: IrcHistory,
+    pub parties: Parties,
+    pub mutes: Mutes,
+    pub cosmetic_log: CosmeticLog,
+    /// Flipped to true once the server starts shutting down
+    pub shutdown: watch::Sender<bool>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl AppState {
+    /// Resolves once the server starts shutting down.
+    pub async fn shutting_down(&self) {
+        let mut rx = self.shutdown.subscribe();
+        while !*rx.borrow() {
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Sends a message to every connection, they pick out what they need.
+    pub fn broadcast(&self, msg: InternalMessages) {
+        self.sessions.broadcast(msg);
+    }
+
+    /// Tells subscribers and friends of a player that came online or went offline.
+    fn send_presence(&self, uuid: Uuid, online: bool) {
+        let friends = self
+            .users
+            .lock()
+            .get(&uuid)
+            .map(|u| u.friends.clone())
+            .unwrap_or_default();
+        self.sessions.send_presence(uuid, online, &friends);
+    }
+
+    /// Registers a new session and tells subscribers if the player came online.
+    pub fn player_connected(&self, uuid: Uuid) {
+        if self.presence.connect(uuid) {
+            self.send_presence(uuid, true);
+        }
+    }
+
+    /// Removes a session and tells subscribers if the player went offline, offline players leave their party.
+    pub fn player_disconnected(&self, session: &Session) {
+        let uuid = session.uuid;
+        self.sessions.unregister(session);
+        if self.presence.disconnect(uuid) {
+            self.send_presence(uuid, false);
+            if let Ok(party) = self.parties.leave(uuid) {
+                self.send_party_update(uuid, party, None, None);
+            }
+        }
+    }
+
+    /// The status of a player if they have one and their privacy setting lets `viewer` see it.
+    pub fn visible_status(&self, viewer: Uuid, uuid: Uuid) -> Option<Status> {
+        let status = self.presence.status(&uuid)?;
+        let users = self.users.lock();
+        let user = users.get(&uuid);
+        let visible = viewer == uuid
+            || match user.map(|u| u.status_privacy).unwrap_or_default() {
+                StatusPrivacy::Everyone => true,
+                StatusPrivacy::Friends => matches!(user, Some(u) if u.friends.contains(&viewer)),
+                StatusPrivacy::Nobody => false,
+            };
+        visible.then_some(status)
+    }
+
+    /// Closes every connection of a player, parked ones included, with the reason shown to the client.
+    pub fn kick(&self, uuid: Uuid, code: u16, reason: String) {
+        self.sessions.send(uuid, InternalMessages::Kick { uuid, code, reason });
+    }
+
+    /// Bans a player for `duration` or forever and kicks them, durations too long to represent are forever too.
+    pub fn ban(&self, uuid: Uuid, reason: String, duration: Option<Duration>) -> Ban {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let ban = Ban {
+            reason,
+            until: duration
+                .and_then(|d| now.checked_add(d))
+                .and_then(|until| u64::try_from(until.as_millis()).ok()),
+        };
+        let mut users = self.users.lock();
+        let user = users.entry(uuid).or_default();
+        user.ban = Some(ban.clone());
+        self.persist_user(uuid, user);
+        drop(users);
+        self.kick(uuid, close_code::BANNED, ban.reason.clone());
+        ban
+    }
+
+    /// Lifts the ban of a player, returns false if they weren't banned.
+    pub fn unban(&self, uuid: Uuid) -> bool {
+        let mut users = self.users.lock();
+        match users.get_mut(&uuid) {
+            Some(user) if user.ban.is_some() => {
+                user.ban = None;
+                self.persist_user(uuid, user);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Sends a party to all its members, a `removed` player is told they are no longer in a party.
+    pub fn send_party_update(
+        &self,
+        requester_id: Uuid,
+        party: Option<Party>,
+        removed: Option<Uuid>,
+        nonce: Option<String>,
+    ) {
+        if let Some(removed) = removed {
+            let msg = InternalMessages::PartyUpdated {
+                to: vec![removed],
+                requester_id,
+                party: None,
+                nonce: nonce.clone(),
+            };
+            self.sessions.send(removed, msg);
+        }
+        if let Some(party) = party {
+            let members = party.members.clone();
+            let msg = InternalMessages::PartyUpdated {
+                to: members.clone(),
+                requester_id,
+                party: Some(party),
+                nonce,
+            };
+            self.sessions.send_many(&members, msg);
+        }
+    }
+
+    /// Sends a message to everyone in an irc channel and adds it to the history.
+    pub fn send_irc(&self, channel: String, sender: Uuid, message: String) {
+        let date = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let msg = self.irc_history.record(channel, sender, message, date);
+        let msg = InternalMessages::IrcCreate {
+            message: msg.message,
+            sender: msg.sender,
+            date: msg.date,
+            channel: msg.channel,
+            id: msg.id,
+        };
+        self.broadcast(msg.clone());
+        let _ = self.tx.send(msg);
+    }
+
+    /// Current users and cosmetics in the shape of the cosmetics file.
+    pub fn cosmetic_file(&self) -> CosmeticFile {
+        let users = self.users.lock();
+        let cosmetics = self.cosmetics.lock();
+        CosmeticFile {
+            cosmetics: cosmetics.clone(),
+            users: users.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Swaps in users and cosmetics loaded from elsewhere, presence is tracked separately and is left alone.
+    pub fn replace_cosmetics(&self, file: CosmeticFile) {
+        let mut users = self.users.lock();
+        let mut cosmetics = self.cosmetics.lock();
+        *users = file.users;
+        *cosmetics = file.cosmetics;
+        self.cosmetic_log.reset();
+    }
+
+    /// Swaps in a cosmetics file that was edited on disk, keeping the changes that were only written through so far.
+    ///
+    /// Both locks are held while those are applied so no change can be made in between and lost.
+    pub fn reload_cosmetics(&self, mut file: CosmeticFile) -> Result<()> {
+        let mut users = self.users.lock();
+        let mut cosmetics = self.cosmetics.lock();
+        self.storage.apply_pending(&mut file)?;
+        *users = file.users;
+        *cosmetics = file.cosmetics;
+        self.cosmetic_log.reset();
+        Ok(())
+    }
+
+    /// Every cosmetic and the cosmetic each user has equipped, with the version of the cosmetic log they are at.
+    pub fn cosmetics_snapshot(&self) -> (u64, Vec<Cosmetic>, HashMap<Uuid, u8>) {
+        let users = self.users.lock();
+        let cosmetics = self.cosmetics.lock();
+        let equipped = users
+            .iter()
+            .filter_map(|(uuid, user)| Some((*uuid, user.enabled_prefix?)))
+            .collect();
+        (self.cosmetic_log.version(), cosmetics.clone(), equipped)
+    }
+
+    /// Adds a change to the cosmetic log and sends it to clients, call this while still holding the lock of what changed.
+    pub fn record_cosmetic_change(&self, change: CosmeticChange) {
+        let delta = self.cosmetic_log.record(change);
+        self.broadcast(InternalMessages::CosmeticsDelta { delta });
+    }
+
+    /// Saves the whole state to storage.
+    pub fn save(&self) -> Result<()> {
+        self.storage.save(&|| self.cosmetic_file())
+    }
+
+    /// Makes sure every change is on disk, on a blocking thread since the json storage writes the whole file.
+    pub async fn checkpoint(self: Arc<Self>) -> Result<()> {
+        tokio::task::spawn_blocking(move || self.storage.checkpoint(&|| self.cosmetic_file())).await?
+    }
+
+    /// Writes a changed user through to storage, call this after updating `users`.
+    pub fn persist_user(&self, uuid: Uuid, user: &User) {
+        if let Err(e) = self.storage.put_user(uuid, user) {
+            tracing::error!("Failed to persist user {}: {:?}", uuid, e);
+        }
+    }
+
+    /// Removes a user from storage, call this after removing it from `users`.
+    pub fn forget_user(&self, uuid: Uuid) {
+        if let Err(e) = self.storage.remove_user(uuid) {
+            tracing::error!("Failed to remove user {}: {:?}", uuid, e);
+        }
+    }
+
+    /// Writes a changed cosmetic through to storage, call this after updating `cosmetics`.
+    pub fn persist_cosmetic(&self, cosmetic: &Cosmetic) {
+        if let Err(e) = self.storage.put_cosmetic(cosmetic) {
+            tracing::error!("Failed to persist cosmetic {}: {:?}", cosmetic.id, e);
+        }
+    }
+
+    /// Removes a cosmetic from storage, call this after removing it from `cosmetics`.
+    pub fn forget_cosmetic(&self, id: u8) {
+        if let Err(e) = self.storage.remove_cosmetic(id) {
+            tracing::error!("Failed to remove cosmetic {}: {:?}", id, e);
+        }
+    }
+}
+
+/// Totals since startup, exposed in the metrics.
+#[derive(Debug, Default)]
+pub struct Counters {
+    /// Sessions closed because they stopped answering pings
+    pub reaped_heartbeat: AtomicUsize,
+    /// Sessions closed because they didn't send anything within the idle timeout
+    pub reaped_idle: AtomicUsize,
+    /// Sessions closed because their outbox was full
+    pub reaped_slow: AtomicUsize,
+    /// Sessions closed because they kept going over the rate limits
+    pub reaped_rate_limited: AtomicUsize,
+    /// Messages rejected by a rate limit
+    pub rate_limited: AtomicUsize,
+    /// Players muted for going over the rate limits
+    pub rate_limit_mutes: AtomicUsize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Cosmetic {
     pub id: u8,
     pub name: String,
@@ -32,12 +317,41 @@ pub struct User {
     pub flags: CosmeticFlags,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub enabled_prefix: Option<u8>,
-    #[serde(default, skip)]
-    pub connected: bool,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub linked_discord: Option<UserId>,
     #[serde(default, skip_serializing_if = "is_false")]
     pub irc_blacklisted: bool,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub friends: HashSet<Uuid>,
+    /// Users that sent this user a friend request
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub friend_requests: HashSet<Uuid>,
+    /// Users this user sent a friend request, counted against the friend limit
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub sent_friend_requests: HashSet<Uuid>,
+    #[serde(default, skip_serializing_if = "StatusPrivacy::is_everyone")]
+    pub status_privacy: StatusPrivacy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ban: Option<Ban>,
+}
+
+impl User {
+    /// The ban of this user if it hasn't expired yet.
+    pub fn active_ban(&self) -> Option<&Ban> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        self.ban.as_ref().filter(|ban| match ban.until {
+            Some(until) => until > now,
+            None => true,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Ban {
+    pub reason: String,
+    /// Milliseconds since the unix epoch, the ban is permanent without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<u64>,
 }
 
 fn is_false(b: &bool) -> bool {
@@ -1,9 +1,11 @@
 use std::num::{NonZeroU32, ParseIntError};
 
-use clap::Parser;
+use clap::{ArgAction, CommandFactory, Parser};
 use governor::Quota;
 use serenity::model::prelude::{ApplicationId, ChannelId, RoleId};
 
+use crate::storage::StorageKind;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
@@ -19,9 +21,69 @@ pub struct Cli {
     /// Cosmetics file
     #[arg(env, long, default_value = "cosmetics.json")]
     pub cosmetics_file: String,
+    /// Storage backend for users and cosmetics
+    #[arg(env, long, value_enum, default_value = "json")]
+    pub storage: StorageKind,
+    /// Sqlite database file, used by the sqlite storage backend
+    #[arg(env, long, default_value = "dws.db")]
+    pub database_file: String,
+    /// Reload the cosmetics file when it is changed on disk, only used by the json storage backend
+    #[arg(env, long, default_value = "true", action = ArgAction::Set)]
+    pub watch_cosmetics: bool,
+    /// Directory to keep snapshots of the cosmetics in
+    #[arg(env, long, default_value = "snapshots")]
+    pub snapshot_dir: String,
+    /// Amount of snapshots to keep, 0 disables snapshots
+    #[arg(env, long, default_value = "10")]
+    pub snapshot_count: usize,
+    /// Seconds to wait for connections and queued messages when shutting down
+    #[arg(env, long, default_value = "10")]
+    pub shutdown_timeout: u64,
     /// Ratelimit per minute
     #[arg(env, long, default_value = "100", value_parser = parse_quota)]
     pub ratelimit_per_minute: Quota,
+    /// Maximum amount of users a single connection can subscribe to the presence of
+    #[arg(env, long, default_value = "250")]
+    pub presence_subscription_limit: usize,
+    /// Seconds a disconnected session can be resumed for, 0 disables resuming
+    #[arg(env, long, default_value = "30")]
+    pub resume_window: u64,
+    /// Maximum amount of messages kept for a disconnected session
+    #[arg(env, long, default_value = "100")]
+    pub resume_buffer: usize,
+    /// Seconds between websocket pings sent to clients, 0 disables them
+    #[arg(env, long, default_value = "30")]
+    pub heartbeat_interval: u64,
+    /// Amount of pings a client can leave unanswered before it is disconnected
+    #[arg(env, long, default_value = "2")]
+    pub heartbeat_missed_pongs: usize,
+    /// Amount of messages queued for a client before messages are dropped or it is disconnected as too slow
+    #[arg(env, long, default_value = "256")]
+    pub outbox_size: usize,
+    /// Seconds a client can go without sending a message before it is disconnected, 0 disables this
+    #[arg(env, long, default_value = "0")]
+    pub idle_timeout: u64,
+    /// Maximum amount of friends and of pending friend requests a user can have
+    #[arg(env, long, default_value = "200")]
+    pub friend_limit: usize,
+    /// Amount of cosmetic changes kept for clients catching up with `/cosmetics/sync`
+    #[arg(env, long, default_value = "1000")]
+    pub cosmetic_log_size: usize,
+    /// Maximum amount of players in a party
+    #[arg(env, long, default_value = "5")]
+    pub party_size: usize,
+    /// Seconds rate limit violations are remembered for when deciding on penalties
+    #[arg(env, long, default_value = "60")]
+    pub ratelimit_violation_window: u64,
+    /// Rate limit violations within the window after which a player is muted in irc and party chat, 0 disables this
+    #[arg(env, long, default_value = "5")]
+    pub ratelimit_mute_after: usize,
+    /// Seconds a player stays muted for going over the rate limit
+    #[arg(env, long, default_value = "300")]
+    pub ratelimit_mute_duration: u64,
+    /// Rate limit violations within the window after which a client is disconnected, 0 disables this
+    #[arg(env, long, default_value = "20")]
+    pub ratelimit_disconnect_after: usize,
     /// Discord bot token
     #[arg(env, long)]
     pub discord_token: String,
@@ -37,6 +99,15 @@ pub struct Cli {
     /// Discord IRC channel
     #[arg(env, long, value_parser  = parse_channel_id)]
     pub discord_irc_channel: Option<ChannelId>,
+    /// Irc channels file, a json array of channels, the global channel always exists
+    #[arg(env, long, default_value = "irc_channels.json")]
+    pub irc_channels_file: String,
+    /// File the irc history is saved to
+    #[arg(env, long, default_value = "irc_history.json")]
+    pub irc_history_file: String,
+    /// Amount of messages kept per irc channel, 0 disables the history
+    #[arg(env, long, default_value = "100")]
+    pub irc_history_size: usize,
     /// Discord linked role
     #[arg(env, long, value_parser  = parse_role_id)]
     pub discord_linked_role: Option<RoleId>,
@@ -48,6 +119,33 @@ pub struct Cli {
     pub influx_url: Option<String>,
 }
 
+/// Maintenance commands, these work on files directly and don't need the server configuration.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub enum Tool {
+    /// Upgrade a cosmetics file to the current version, the original is kept as a backup
+    Migrate {
+        /// Cosmetics file to migrate
+        #[arg(env = "COSMETICS_FILE", long, default_value = "cosmetics.json")]
+        file: String,
+    },
+    /// Check a cosmetics file for problems, exits with a non zero code if there are any
+    Check {
+        /// Cosmetics file to check
+        #[arg(env = "COSMETICS_FILE", long, default_value = "cosmetics.json")]
+        file: String,
+    },
+}
+
+impl Tool {
+    /// Parses the arguments as a tool if the first one names a tool.
+    pub fn from_args() -> Option<Self> {
+        let name = std::env::args().nth(1)?;
+        Tool::command().find_subcommand(&name)?;
+        Some(Tool::parse())
+    }
+}
+
 fn parse_app_id(src: &str) -> Result<ApplicationId, ParseIntError> {
     src.parse::<u64>().map(ApplicationId::new)
 }
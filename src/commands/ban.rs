@@ -0,0 +1,68 @@
+use std::{sync::Arc, time::Duration};
+
+use serenity::{
+    builder::{CreateCommand, CreateCommandOption, CreateInteractionResponseMessage},
+    model::prelude::{command::CommandOptionType, CommandInteraction, ResolvedValue},
+};
+use uuid::Uuid;
+
+use crate::app_state::AppState;
+
+pub fn run(cmd: CommandInteraction, state: Arc<AppState>) -> CreateInteractionResponseMessage {
+    let options = cmd.data.options();
+    let option = |name: &str| options.iter().find(|o| o.name == name).map(|o| o.value.clone());
+    let uuid = match option("uuid") {
+        Some(ResolvedValue::String(v)) => match Uuid::parse_str(v) {
+            Ok(v) => v,
+            Err(_) => return CreateInteractionResponseMessage::new().content("Invalid UUID".to_string()),
+        },
+        _ => return CreateInteractionResponseMessage::new().content("Invalid UUID".to_string()),
+    };
+
+    if matches!(option("act"), Some(ResolvedValue::String("remove"))) {
+        return CreateInteractionResponseMessage::new().content(if state.unban(uuid) {
+            format!("Unbanned {}", uuid)
+        } else {
+            format!("{} is not banned", uuid)
+        });
+    }
+
+    let reason = match option("reason") {
+        Some(ResolvedValue::String(v)) => v.to_owned(),
+        _ => "Banned by an admin".to_owned(),
+    };
+    let duration = match option("minutes") {
+        Some(ResolvedValue::Integer(v)) if v > 0 => (v as u64).checked_mul(60).map(Duration::from_secs),
+        Some(ResolvedValue::Integer(_)) => {
+            return CreateInteractionResponseMessage::new().content("A ban has to last at least a minute".to_string())
+        }
+        _ => None,
+    };
+    let ban = state.ban(uuid, reason, duration);
+    CreateInteractionResponseMessage::new().content(match (ban.until, duration) {
+        (Some(_), Some(duration)) => format!("Banned {} for {} minutes", uuid, duration.as_secs() / 60),
+        _ => format!("Banned {} permanently", uuid),
+    })
+}
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new("ban")
+        .description("Ban or unban a user, banning disconnects all their clients")
+        .add_option(
+            CreateCommandOption::new(CommandOptionType::String, "act", "act")
+                .add_string_choice("Add", "add")
+                .add_string_choice("Remove", "remove")
+                .required(true),
+        )
+        .add_option(CreateCommandOption::new(CommandOptionType::String, "uuid", "The UUID of the user").required(true))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::String,
+            "reason",
+            "Reason shown to the user",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::Integer,
+            "minutes",
+            "How long the ban lasts, permanent if left out",
+        ))
+}
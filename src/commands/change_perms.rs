@@ -26,7 +26,8 @@ pub fn run(cmd: CommandInteraction, state: Arc<AppState>) -> CreateInteractionRe
     })
     .unwrap();
 
-    let user = match state.users.lock().get(&uuid) {
+    let mut users = state.users.lock();
+    let user = match users.get(&uuid) {
         Some(v) => {
             let mut v = v.clone();
             v.flags = bits;
@@ -39,7 +40,8 @@ pub fn run(cmd: CommandInteraction, state: Arc<AppState>) -> CreateInteractionRe
         },
     };
 
-    state.users.lock().insert(uuid, user);
+    state.persist_user(uuid, &user);
+    users.insert(uuid, user);
     CreateInteractionResponseMessage::new().content(format!("Permission bits changed to {:b} for {}", bits, uuid))
 }
 
@@ -1,7 +1,4 @@
-use std::{
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::sync::Arc;
 
 use serenity::{
     builder::{CreateCommand, CreateCommandOption, CreateInteractionResponseMessage},
@@ -11,7 +8,6 @@ use uuid::Uuid;
 
 use crate::{
     app_state::{AppState, User},
-    messages::InternalMessages,
     utils::sanitize::sanitize_message,
 };
 
@@ -43,6 +39,7 @@ pub async fn run(cmd: CommandInteraction, state: Arc<AppState>, admin: bool) ->
                 Some(v) => {
                     let mut user = v.clone();
                     user.irc_blacklisted = add;
+                    state.persist_user(uuid, &user);
                     users.insert(uuid, user);
                 }
                 None => {
@@ -50,6 +47,7 @@ pub async fn run(cmd: CommandInteraction, state: Arc<AppState>, admin: bool) ->
                         irc_blacklisted: add,
                         ..Default::default()
                     };
+                    state.persist_user(uuid, &user);
                     users.insert(uuid, user);
                 }
             };
@@ -75,12 +73,16 @@ pub async fn run(cmd: CommandInteraction, state: Arc<AppState>, admin: bool) ->
                 }
             };
 
+            let channel = state.irc.by_discord_channel(cmd.channel_id);
+            let flags = state.users.lock().get(&uuid).map(|u| u.flags).unwrap_or_default();
+            if !channel.allows(flags) {
+                return CreateInteractionResponseMessage::new()
+                    .ephemeral(true)
+                    .content(format!("You can't talk in {}", channel.name));
+            }
+
             let msg = sanitize_message(&options.get(0).unwrap().value.string());
-            let _ = state.tx.send(InternalMessages::IrcCreate {
-                sender: uuid,
-                message: msg,
-                date: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
-            });
+            state.send_irc(channel.name.clone(), uuid, msg);
             CreateInteractionResponseMessage::new().ephemeral(true).content("Send!")
         }
 
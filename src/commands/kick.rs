@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use serenity::{
+    builder::{CreateCommand, CreateCommandOption, CreateInteractionResponseMessage},
+    model::prelude::{command::CommandOptionType, CommandInteraction, ResolvedValue},
+};
+use uuid::Uuid;
+
+use crate::{app_state::AppState, messages::close_code};
+
+pub fn run(cmd: CommandInteraction, state: Arc<AppState>) -> CreateInteractionResponseMessage {
+    let options = cmd.data.options();
+    let option = |name: &str| options.iter().find(|o| o.name == name).map(|o| o.value.clone());
+    let uuid = match option("uuid") {
+        Some(ResolvedValue::String(v)) => match Uuid::parse_str(v) {
+            Ok(v) => v,
+            Err(_) => return CreateInteractionResponseMessage::new().content("Invalid UUID".to_string()),
+        },
+        _ => return CreateInteractionResponseMessage::new().content("Invalid UUID".to_string()),
+    };
+    let reason = match option("reason") {
+        Some(ResolvedValue::String(v)) => v.to_owned(),
+        _ => "Kicked by an admin".to_owned(),
+    };
+
+    if !state.presence.is_online(&uuid) {
+        return CreateInteractionResponseMessage::new().content(format!("{} is not online", uuid));
+    }
+    state.kick(uuid, close_code::KICKED, reason);
+    CreateInteractionResponseMessage::new().content(format!("Kicked {}", uuid))
+}
+
+pub fn register() -> CreateCommand {
+    CreateCommand::new("kick")
+        .description("Disconnect all clients of a user")
+        .add_option(CreateCommandOption::new(CommandOptionType::String, "uuid", "The UUID of the user").required(true))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::String,
+            "reason",
+            "Reason shown to the user",
+        ))
+}
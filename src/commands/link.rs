@@ -27,13 +27,12 @@ pub async fn run(cmd: CommandInteraction, state: Arc<AppState>) -> CreateInterac
         return CreateInteractionResponseMessage::new().content("Discord link does not match!");
     }
     let mut f = state.users.lock();
-    f.insert(
-        data.uuid,
-        User {
-            linked_discord: Some(user.id),
-            ..Default::default()
-        },
-    );
+    let linked = User {
+        linked_discord: Some(user.id),
+        ..f.get(&data.uuid).cloned().unwrap_or_default()
+    };
+    state.persist_user(data.uuid, &linked);
+    f.insert(data.uuid, linked);
 
     CreateInteractionResponseMessage::new().content(format!("Linked {} to {} ({})", username, mcusername, data.uuid))
 }
@@ -5,8 +5,10 @@ use serenity::{builder::*, http::Http, model::application::interaction::applicat
 
 use crate::{app_state::AppState, config::CONFIG, Result};
 
+mod ban;
 mod change_perms;
 mod irc;
+mod kick;
 mod link;
 mod users;
 
@@ -25,6 +27,8 @@ pub async fn handle_command(interaction: CommandInteraction, state: Arc<AppState
     let res = match (interaction.data.name.as_str(), admin) {
         ("users", _) => users::run(interaction, state),
         ("change_perms", true) => change_perms::run(interaction, state),
+        ("kick", true) => kick::run(interaction, state),
+        ("ban", true) => ban::run(interaction, state),
         ("irc", _) => irc::run(interaction, state, admin).await,
         ("link", _) => link::run(interaction, state).await,
         _ => CreateInteractionResponseMessage::new().content("404 command not found lol".to_string()),
@@ -37,6 +41,8 @@ pub async fn register() -> Result<()> {
         change_perms::register(),
         irc::register(),
         link::register(),
+        kick::register(),
+        ban::register(),
     ])
     .await?;
     Ok(())
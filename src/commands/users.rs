@@ -8,7 +8,7 @@ use serenity::{
 use crate::app_state::AppState;
 
 pub fn run(_: CommandInteraction, state: Arc<AppState>) -> CreateInteractionResponseMessage {
-    let connected_users = state.users.lock().iter().filter(|x| x.1.connected).count();
+    let connected_users = state.presence.online_count();
     CreateInteractionResponseMessage::new().content(format!("Connected users: {}", connected_users))
 }
 
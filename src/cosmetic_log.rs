@@ -0,0 +1,129 @@
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::app_state::Cosmetic;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CosmeticChange {
+    /// A user equipped a cosmetic, or unequipped it when `cosmetic_id` is missing
+    Equipped {
+        uuid: Uuid,
+        cosmetic_id: Option<u8>,
+    },
+    /// A cosmetic was added or changed
+    Added {
+        cosmetic: Cosmetic,
+    },
+    Removed {
+        id: u8,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CosmeticDelta {
+    pub version: u64,
+    pub change: CosmeticChange,
+}
+
+#[derive(Debug)]
+struct Inner {
+    version: u64,
+    /// Version of the oldest state the kept changes can be applied to
+    oldest: u64,
+    changes: VecDeque<CosmeticDelta>,
+}
+
+/// The last `size` changes to users' cosmetics and the cosmetics themselves.
+///
+/// Versions start at the startup time in milliseconds so they keep going up across restarts, clients that are further
+/// behind than the kept changes need a snapshot.
+#[derive(Debug)]
+pub struct CosmeticLog {
+    size: usize,
+    inner: Mutex<Inner>,
+}
+
+impl CosmeticLog {
+    pub fn new(size: usize) -> Self {
+        let version = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        Self {
+            size,
+            inner: Mutex::new(Inner {
+                version,
+                oldest: version,
+                changes: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.inner.lock().version
+    }
+
+    /// Gives the change the next version and remembers it, call this while holding the lock of what changed.
+    pub fn record(&self, change: CosmeticChange) -> CosmeticDelta {
+        let mut inner = self.inner.lock();
+        inner.version += 1;
+        let delta = CosmeticDelta {
+            version: inner.version,
+            change,
+        };
+        inner.changes.push_back(delta.clone());
+        while inner.changes.len() > self.size {
+            if let Some(dropped) = inner.changes.pop_front() {
+                inner.oldest = dropped.version;
+            }
+        }
+        delta
+    }
+
+    /// Forgets all changes, for when everything was replaced at once.
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock();
+        inner.version += 1;
+        inner.oldest = inner.version;
+        inner.changes.clear();
+    }
+
+    /// The changes after `version`, None when they aren't all kept anymore or the version is unknown.
+    pub fn since(&self, version: u64) -> Option<Vec<CosmeticDelta>> {
+        let inner = self.inner.lock();
+        if version < inner.oldest || version > inner.version {
+            return None;
+        }
+        Some(inner.changes.iter().filter(|d| d.version > version).cloned().collect())
+    }
+}
+
+#[test]
+fn changes_since_version() {
+    let log = CosmeticLog::new(2);
+    let start = log.version();
+    let uuid = Uuid::new_v4();
+    let equip = |id| CosmeticChange::Equipped {
+        uuid,
+        cosmetic_id: Some(id),
+    };
+
+    let first = log.record(equip(1));
+    assert_eq!(first.version, start + 1);
+    assert_eq!(log.since(start).unwrap(), std::slice::from_ref(&first));
+    assert_eq!(log.since(first.version).unwrap(), []);
+    assert_eq!(log.since(first.version + 1), None);
+
+    let second = log.record(equip(2));
+    let third = log.record(CosmeticChange::Removed { id: 1 });
+    assert_eq!(log.since(start), None);
+    assert_eq!(log.since(first.version).unwrap(), [second, third.clone()]);
+
+    log.reset();
+    assert_eq!(log.since(third.version), None);
+    assert_eq!(log.since(log.version()).unwrap(), []);
+}
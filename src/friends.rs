@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    app_state::User,
+    messages::{ErrorCode, Rejection},
+};
+
+/// How a user relates to another user, from the first user's side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FriendStatus {
+    None,
+    /// The other user sent a friend request
+    Incoming,
+    /// A friend request was sent to the other user
+    Outgoing,
+    Friends,
+}
+
+pub type Users = HashMap<Uuid, User>;
+
+pub fn status(users: &Users, uuid: Uuid, other: Uuid) -> FriendStatus {
+    let user = |u: Uuid| users.get(&u);
+    if matches!(user(uuid), Some(u) if u.friends.contains(&other)) {
+        FriendStatus::Friends
+    } else if matches!(user(uuid), Some(u) if u.friend_requests.contains(&other)) {
+        FriendStatus::Incoming
+    } else if matches!(user(other), Some(u) if u.friend_requests.contains(&uuid)) {
+        FriendStatus::Outgoing
+    } else {
+        FriendStatus::None
+    }
+}
+
+/// Sends a friend request, if `to` already sent one to `from` they become friends.
+///
+/// Players without a user can only be sent a request while `to_online`, so made up uuids don't create users.
+pub fn request(users: &mut Users, from: Uuid, to: Uuid, to_online: bool, limit: usize) -> Result<(), Rejection> {
+    if from == to {
+        return Err((ErrorCode::Conflict, "You can't friend yourself"));
+    }
+    match status(users, from, to) {
+        FriendStatus::Friends => Err((ErrorCode::Conflict, "You are already friends")),
+        FriendStatus::Outgoing => Err((ErrorCode::Conflict, "You already sent a friend request")),
+        FriendStatus::Incoming => accept(users, from, to, limit),
+        FriendStatus::None => {
+            if !to_online && !users.contains_key(&to) {
+                return Err((ErrorCode::NotFound, "This player is not online"));
+            }
+            if users.get(&from).map_or(0, |u| u.sent_friend_requests.len()) >= limit {
+                return Err((ErrorCode::LimitReached, "You sent too many friend requests"));
+            }
+            if users.get(&to).map_or(0, |u| u.friend_requests.len()) >= limit {
+                return Err((ErrorCode::LimitReached, "This user has too many friend requests"));
+            }
+            users.entry(to).or_default().friend_requests.insert(from);
+            users.entry(from).or_default().sent_friend_requests.insert(to);
+            Ok(())
+        }
+    }
+}
+
+/// Accepts the friend request `from` sent to `uuid`.
+pub fn accept(users: &mut Users, uuid: Uuid, from: Uuid, limit: usize) -> Result<(), Rejection> {
+    if status(users, uuid, from) != FriendStatus::Incoming {
+        return Err((ErrorCode::NotFound, "There is no friend request from this user"));
+    }
+    let full = |u: Uuid| users.get(&u).map_or(0, |u| u.friends.len()) >= limit;
+    if full(uuid) || full(from) {
+        return Err((ErrorCode::LimitReached, "Too many friends"));
+    }
+    let user = users.entry(uuid).or_default();
+    user.friend_requests.remove(&from);
+    user.friends.insert(from);
+    let user = users.entry(from).or_default();
+    user.sent_friend_requests.remove(&uuid);
+    user.friends.insert(uuid);
+    Ok(())
+}
+
+/// Declines the friend request `from` sent to `uuid`.
+pub fn decline(users: &mut Users, uuid: Uuid, from: Uuid) -> Result<(), Rejection> {
+    let removed = users.get_mut(&uuid).map(|u| u.friend_requests.remove(&from));
+    if removed != Some(true) {
+        return Err((ErrorCode::NotFound, "There is no friend request from this user"));
+    }
+    if let Some(user) = users.get_mut(&from) {
+        user.sent_friend_requests.remove(&uuid);
+    }
+    Ok(())
+}
+
+/// Removes a friend or takes back a friend request sent to them.
+pub fn remove(users: &mut Users, uuid: Uuid, other: Uuid) -> Result<(), Rejection> {
+    match status(users, uuid, other) {
+        FriendStatus::Friends => {
+            for (a, b) in [(uuid, other), (other, uuid)] {
+                if let Some(user) = users.get_mut(&a) {
+                    user.friends.remove(&b);
+                }
+            }
+            Ok(())
+        }
+        FriendStatus::Outgoing => decline(users, other, uuid),
+        _ => Err((ErrorCode::NotFound, "This user is not your friend")),
+    }
+}
+
+#[test]
+fn friend_requests() {
+    let mut users = Users::new();
+    let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+    assert!(request(&mut users, a, a, true, 10).is_err());
+
+    request(&mut users, a, b, true, 10).unwrap();
+    assert_eq!(status(&users, a, b), FriendStatus::Outgoing);
+    assert_eq!(status(&users, b, a), FriendStatus::Incoming);
+    assert!(request(&mut users, a, b, true, 10).is_err());
+    decline(&mut users, b, a).unwrap();
+    assert_eq!(status(&users, a, b), FriendStatus::None);
+
+    request(&mut users, a, b, true, 10).unwrap();
+    accept(&mut users, b, a, 10).unwrap();
+    assert_eq!(
+        (status(&users, a, b), status(&users, b, a)),
+        (FriendStatus::Friends, FriendStatus::Friends)
+    );
+
+    remove(&mut users, b, a).unwrap();
+    assert_eq!(status(&users, a, b), FriendStatus::None);
+    assert!(remove(&mut users, b, a).is_err());
+
+    // Requesting someone who already sent you a request accepts it
+    request(&mut users, a, b, true, 10).unwrap();
+    request(&mut users, b, a, true, 10).unwrap();
+    assert_eq!(status(&users, a, b), FriendStatus::Friends);
+}
+
+#[test]
+fn friend_requests_are_limited() {
+    let mut users = Users::new();
+    let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    assert!(request(&mut users, a, b, false, 1).is_err());
+    assert!(users.is_empty());
+
+    request(&mut users, a, b, true, 1).unwrap();
+    assert_eq!(
+        request(&mut users, a, c, true, 1).unwrap_err().0,
+        ErrorCode::LimitReached
+    );
+    // Known users can be sent requests while offline
+    decline(&mut users, b, a).unwrap();
+    request(&mut users, a, b, false, 1).unwrap();
+    accept(&mut users, b, a, 1).unwrap();
+    assert!(users[&a].sent_friend_requests.is_empty());
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{error::Result, utils::atomic_write};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IrcMessage {
+    /// Increases with every message and is never reused, also not across restarts since ids start at the startup time
+    /// in microseconds
+    pub id: u64,
+    pub message: String,
+    pub sender: Uuid,
+    pub date: u64,
+    pub channel: String,
+}
+
+/// The last `size` messages of every irc channel.
+#[derive(Debug)]
+pub struct IrcHistory {
+    size: usize,
+    channels: Mutex<HashMap<String, VecDeque<IrcMessage>>>,
+    next_id: AtomicU64,
+}
+
+impl IrcHistory {
+    pub fn new(size: usize) -> Self {
+        // Not derived from the saved history alone, messages sent after the last save were already seen by clients
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+        Self {
+            size,
+            channels: Default::default(),
+            next_id: AtomicU64::new(start),
+        }
+    }
+
+    /// Loads the history saved by [`IrcHistory::save`], a missing file gives an empty history.
+    pub fn load(path: &str, size: usize) -> Result<Self> {
+        let history = Self::new(size);
+        let mut channels: HashMap<String, VecDeque<IrcMessage>> = match fs::read_to_string(path) {
+            Ok(file) => serde_json::from_str(&file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let last_id = channels.values().flatten().map(|m| m.id).max().unwrap_or_default();
+        for messages in channels.values_mut() {
+            while messages.len() > size {
+                messages.pop_front();
+            }
+        }
+        history.next_id.fetch_max(last_id + 1, Ordering::SeqCst);
+        *history.channels.lock() = channels;
+        Ok(history)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let contents = serde_json::to_string(&*self.channels.lock())?;
+        atomic_write(path, &contents)?;
+        Ok(())
+    }
+
+    /// Gives the message an id and remembers it.
+    pub fn record(&self, channel: String, sender: Uuid, message: String, date: u64) -> IrcMessage {
+        let mut channels = self.channels.lock();
+        let msg = IrcMessage {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            message,
+            sender,
+            date,
+            channel,
+        };
+        if self.size > 0 {
+            let messages = channels.entry(msg.channel.clone()).or_default();
+            messages.push_back(msg.clone());
+            if messages.len() > self.size {
+                messages.pop_front();
+            }
+        }
+        msg
+    }
+
+    /// Up to `limit` of the newest messages older than `before`, oldest first.
+    pub fn page(&self, channel: &str, before: Option<u64>, limit: usize) -> Vec<IrcMessage> {
+        let channels = self.channels.lock();
+        let messages = match channels.get(channel) {
+            Some(messages) => messages,
+            None => return Vec::new(),
+        };
+        let end = match before {
+            Some(before) => messages.partition_point(|m| m.id < before),
+            None => messages.len(),
+        };
+        messages.range(end.saturating_sub(limit)..end).cloned().collect()
+    }
+}
+
+#[test]
+fn pages_through_history() {
+    let history = IrcHistory::new(3);
+    let sender = Uuid::new_v4();
+    let ids = (0..4)
+        .map(|i| history.record("global".to_owned(), sender, i.to_string(), 0).id)
+        .collect::<Vec<_>>();
+    history.record("other".to_owned(), sender, "hi".to_owned(), 0);
+
+    let page = |before, limit| {
+        history
+            .page("global", before, limit)
+            .into_iter()
+            .map(|m| m.message)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(page(None, 10), ["1", "2", "3"]);
+    assert_eq!(page(None, 2), ["2", "3"]);
+    assert_eq!(page(Some(ids[3]), 1), ["2"]);
+    assert_eq!(page(Some(ids[1]), 10), Vec::<String>::new());
+    assert!(history.page("missing", None, 10).is_empty());
+}
+
+#[test]
+fn ids_are_not_reused_after_a_restart() {
+    let path = std::env::temp_dir().join(format!("dws-irc-{}.json", Uuid::new_v4()));
+    let path = path.to_str().unwrap();
+    let sender = Uuid::new_v4();
+    let history = IrcHistory::load(path, 0).unwrap();
+    let first = history.record("global".to_owned(), sender, "hi".to_owned(), 0).id;
+    history.save(path).unwrap();
+
+    // Nothing was kept, a restart still has to hand out higher ids
+    let history = IrcHistory::load(path, 0).unwrap();
+    assert!(history.record("global".to_owned(), sender, "hi".to_owned(), 0).id > first);
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,80 @@
+use std::{collections::HashMap, fs, io};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serenity::model::prelude::ChannelId;
+
+use crate::{bail, bitflags::CosmeticFlags, config::CONFIG, error::Result};
+
+pub mod history;
+
+/// Channel every client is in when it connects, bridged to `--discord-irc-channel` by default
+pub const GLOBAL_CHANNEL: &str = "global";
+
+pub fn global_channel() -> String {
+    GLOBAL_CHANNEL.to_owned()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IrcChannel {
+    pub name: String,
+    /// Flags a user needs to join and talk in this channel
+    #[serde(default, skip_serializing_if = "CosmeticFlags::is_empty")]
+    pub required_flags: CosmeticFlags,
+    /// Discord channel messages are relayed to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discord_channel: Option<ChannelId>,
+}
+
+impl IrcChannel {
+    pub fn allows(&self, flags: CosmeticFlags) -> bool {
+        flags.contains(self.required_flags)
+    }
+}
+
+/// The irc channels from `--irc-channels-file`, which is a json array of channels.
+#[derive(Debug, Default)]
+pub struct IrcChannels {
+    channels: HashMap<String, IrcChannel>,
+}
+
+impl IrcChannels {
+    /// Loads the channels, a missing file only gives the global channel.
+    pub fn load(path: &str) -> Result<Self> {
+        let channels: Vec<IrcChannel> = match fs::read_to_string(path) {
+            Ok(file) => serde_json::from_str(&file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut map = HashMap::new();
+        for channel in channels {
+            if channel.name.is_empty() {
+                bail!("irc channel names can't be empty");
+            }
+            if map.contains_key(&channel.name) {
+                return Err(anyhow!("irc channel {} is defined twice", channel.name).into());
+            }
+            map.insert(channel.name.clone(), channel);
+        }
+        let global = map.entry(global_channel()).or_insert_with(|| IrcChannel {
+            name: global_channel(),
+            required_flags: CosmeticFlags::empty(),
+            discord_channel: None,
+        });
+        global.discord_channel = global.discord_channel.or(CONFIG.discord_irc_channel);
+        Ok(Self { channels: map })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&IrcChannel> {
+        self.channels.get(name)
+    }
+
+    /// The channel bridged to a discord channel, falls back to the global channel.
+    pub fn by_discord_channel(&self, id: ChannelId) -> &IrcChannel {
+        self.channels
+            .values()
+            .find(|c| c.discord_channel == Some(id))
+            .unwrap_or(&self.channels[GLOBAL_CHANNEL])
+    }
+}
@@ -2,7 +2,10 @@
 
 use std::{
     env,
-    sync::{atomic::AtomicU16, Arc},
+    sync::{
+        atomic::{AtomicU16, AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
@@ -14,19 +17,37 @@ use futures_util::future::join3;
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 use serenity::builder::CreateMessage;
-use tokio::{sync::broadcast as tokio_broadcast, time::sleep};
+use tokio::{
+    sync::{
+        broadcast::{
+            self as tokio_broadcast,
+            error::{RecvError, TryRecvError},
+        },
+        watch,
+    },
+    time::{sleep, timeout},
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::{
     api::*,
     app_state::AppState,
+    cli::Tool,
     commands::{register, REST},
     config::CONFIG,
+    cosmetic_log::CosmeticLog,
     error::Result,
+    irc::{history::IrcHistory, IrcChannels},
     messages::InternalMessages,
+    sessions::SessionRegistry,
+    storage::StorageKind,
     utils::{
-        retrieve_cosmetics::{retrieve_cosmetics, CosmeticFile},
-        set_ctrlc, uuid_to_username, Influx,
+        check::check_file,
+        hot_reload::watch_cosmetics,
+        retrieve_cosmetics::{migrate_file, retrieve_cosmetics},
+        shutdown_signal,
+        snapshots::write_snapshot,
+        uuid_to_username, Influx,
     },
 };
 
@@ -36,8 +57,16 @@ pub mod bitflags;
 pub mod cli;
 pub mod commands;
 pub mod config;
+pub mod cosmetic_log;
 pub mod error;
+pub mod friends;
+pub mod irc;
 pub mod messages;
+pub mod party;
+pub mod penalties;
+pub mod presence;
+pub mod sessions;
+pub mod storage;
 pub mod utils;
 
 mod api;
@@ -49,6 +78,18 @@ async fn main() -> Result<()> {
         source::write_sources();
     }
 
+    if let Some(tool) = Tool::from_args() {
+        return match tool {
+            Tool::Migrate { file } => migrate_file(&file),
+            Tool::Check { file } => {
+                if !check_file(&file) {
+                    std::process::exit(1);
+                }
+                Ok(())
+            }
+        };
+    }
+
     // Load config
     Lazy::force(&CONFIG);
 
@@ -70,16 +111,43 @@ async fn main() -> Result<()> {
 
     let (tx, mut rx) = tokio_broadcast::channel::<InternalMessages>(100);
 
-    let cosmetics = retrieve_cosmetics().await;
+    let storage = storage::open(CONFIG.storage, &CONFIG.cosmetics_file, &CONFIG.database_file)?;
+    let cosmetics = retrieve_cosmetics(&*storage);
 
     let app_state = Arc::new(AppState {
         tx: tx.clone(),
+        sessions: SessionRegistry::new(CONFIG.outbox_size),
         cosmetics: Mutex::new(cosmetics.cosmetics),
         users: Mutex::new(cosmetics.users),
         messages_sec: AtomicU16::new(0),
+        storage,
+        connections: AtomicUsize::new(0),
+        presence: Default::default(),
+        resumable: Default::default(),
+        counters: Default::default(),
+        irc: IrcChannels::load(&CONFIG.irc_channels_file)?,
+        irc_history: IrcHistory::load(&CONFIG.irc_history_file, CONFIG.irc_history_size)?,
+        parties: Default::default(),
+        mutes: Default::default(),
+        cosmetic_log: CosmeticLog::new(CONFIG.cosmetic_log_size),
+        shutdown: watch::channel(false).0,
     });
 
-    set_ctrlc(app_state.clone())?;
+    if CONFIG.watch_cosmetics && CONFIG.storage == StorageKind::Json {
+        let app_state_clone = app_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = watch_cosmetics(app_state_clone).await {
+                tracing::error!("Failed to watch cosmetics file: {:?}", e);
+            }
+        });
+    }
+
+    let app_state_clone = app_state.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("Shutting down...");
+        let _ = app_state_clone.shutdown.send(true);
+    });
 
     let app_state_clone = app_state.clone();
 
@@ -90,13 +158,23 @@ async fn main() -> Result<()> {
         loop {
             interval.tick().await;
 
-            let file = CosmeticFile {
-                cosmetics: app_state_clone.cosmetics.lock().clone(),
-                users: app_state_clone.users.lock().clone(),
-            };
-            tokio::fs::write(&CONFIG.cosmetics_file, serde_json::to_string_pretty(&file).unwrap())
-                .await
-                .expect("Failed to write cosmetics file");
+            while let Err(e) = app_state_clone.clone().checkpoint().await {
+                tracing::error!("Failed to save cosmetics, retrying in 10 seconds: {:?}", e);
+                sleep(Duration::from_secs(10)).await;
+            }
+
+            if let Err(e) = app_state_clone.irc_history.save(&CONFIG.irc_history_file) {
+                tracing::error!("Failed to save irc history: {:?}", e);
+            }
+
+            if CONFIG.snapshot_count > 0 {
+                let file = app_state_clone.cosmetic_file();
+                match write_snapshot(&CONFIG.snapshot_dir, CONFIG.snapshot_count, &file) {
+                    Ok(Some(name)) => tracing::debug!("Wrote snapshot {}", name),
+                    Ok(None) => {}
+                    Err(e) => tracing::error!("Failed to write snapshot: {:?}", e),
+                }
+            }
         }
     });
 
@@ -125,22 +203,44 @@ async fn main() -> Result<()> {
             .route("/users", get(admin::users::get_users))
             .route("/users", post(admin::users::add_user))
             .route("/users", delete(admin::users::remove_user))
+            .route("/users/kick", post(admin::users::kick_user))
+            .route("/users/ban", post(admin::users::ban_user))
+            .route("/users/ban", delete(admin::users::unban_user))
             .route("/cosmetics", get(admin::cosmetics::get_cosmetics))
             .route("/cosmetics", post(admin::cosmetics::add_cosmetic))
             .route("/cosmetics", delete(admin::cosmetics::remove_cosmetic))
             .route("/cosmetics/update", post(admin::cosmetics::force_update))
             .route("/uuids_to_usernames", post(admin::users::uuids_to_usernames))
+            .route("/snapshots", get(admin::snapshots::get_snapshots))
+            .route("/snapshots/diff", get(admin::snapshots::diff_snapshot))
+            .route("/snapshots/restore", post(admin::snapshots::restore_snapshot))
     } else {
         Router::with_state(app_state.clone())
     };
 
     let (r, r2, _) = join3(
-        axum::Server::bind(&addr).serve(app.into_make_service()),
-        axum::Server::bind(&admin_addr).serve(admin.into_make_service()),
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(app_state.shutting_down()),
+        axum::Server::bind(&admin_addr)
+            .serve(admin.into_make_service())
+            .with_graceful_shutdown(app_state.shutting_down()),
         async {
-            while let Ok(msg) = rx.recv().await {
-                if let Err(e) = handle_internal(msg, &app_state, &tx).await {
-                    tracing::error!("Error handling internal message: {:?}", e);
+            loop {
+                let msg = tokio::select! {
+                    msg = rx.recv() => msg,
+                    _ = app_state.shutting_down() => break,
+                };
+                match msg {
+                    Ok(msg) => {
+                        if let Err(e) = handle_internal(msg, &app_state).await {
+                            tracing::error!("Error handling internal message: {:?}", e);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Skipped {} internal messages", skipped);
+                    }
+                    Err(_) => break,
                 }
             }
         },
@@ -148,46 +248,54 @@ async fn main() -> Result<()> {
     .await;
     r?;
     r2?;
+
+    // Connections close themselves once they see the shutdown, give them and the messages they queue some time
+    let drain = async {
+        loop {
+            match rx.try_recv() {
+                Ok(msg) => {
+                    if let Err(e) = handle_internal(msg, &app_state).await {
+                        tracing::error!("Error handling internal message: {:?}", e);
+                    }
+                }
+                Err(TryRecvError::Lagged(_)) => {}
+                Err(TryRecvError::Empty) if app_state.connections.load(Ordering::SeqCst) > 0 => {
+                    sleep(Duration::from_millis(50)).await
+                }
+                Err(_) => break,
+            }
+        }
+    };
+    if timeout(Duration::from_secs(CONFIG.shutdown_timeout), drain)
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Gave up waiting for {} connections and {} queued messages",
+            app_state.connections.load(Ordering::SeqCst),
+            rx.len()
+        );
+    }
+
+    match app_state.clone().checkpoint().await {
+        Ok(()) => tracing::info!("Cosmetics saved"),
+        // Changes are still in the journal, they will be replayed on the next start
+        Err(e) => tracing::error!("Failed to save cosmetics: {:?}", e),
+    }
+    if let Err(e) = app_state.irc_history.save(&CONFIG.irc_history_file) {
+        tracing::error!("Failed to save irc history: {:?}", e);
+    }
     Ok(())
 }
 
-async fn handle_internal(
-    msg: InternalMessages,
-    state: &Arc<AppState>,
-    tx: &tokio_broadcast::Sender<InternalMessages>,
-) -> Result<()> {
+async fn handle_internal(msg: InternalMessages, state: &Arc<AppState>) -> Result<()> {
     match msg {
-        InternalMessages::RequestUser {
-            user_id,
-            requester_id,
-            nonce,
-        } => {
-            tokio::spawn(
-                Influx::new("request_online")
-                    .label("uuid", &requester_id.to_string())
-                    .value("user", &user_id.to_string())
-                    .send(),
-            );
-            let is_online = state
-                .users
-                .lock()
-                .get(&user_id)
-                .map(|x| x.connected)
-                .unwrap_or_default();
-
-            let msg = InternalMessages::UserRequestResponse {
-                is_online,
-                requester_id,
-                user_id,
-                nonce,
-            };
-            let _ = tx.send(msg);
-        }
         InternalMessages::IrcCreate {
             message,
             sender,
-            date: _,
-        } => match CONFIG.discord_irc_channel {
+            channel,
+            ..
+        } => match state.irc.get(&channel).and_then(|c| c.discord_channel) {
             Some(channel) => {
                 let data = uuid_to_username(sender).await?;
                 channel
@@ -201,31 +309,6 @@ async fn handle_internal(
             }
             None => {}
         },
-        InternalMessages::RequestUsersBulk {
-            user_ids,
-            requester_id,
-            nonce,
-        } => {
-            tokio::spawn(
-                Influx::new("bulk_request_online")
-                    .label("uuid", &requester_id.to_string())
-                    .send(),
-            );
-            let users = state.users.lock();
-            let list = user_ids
-                .into_iter()
-                .map(|user_id| {
-                    let is_online = users.get(&user_id).map(|x| x.connected).unwrap_or_default();
-                    (user_id, is_online)
-                })
-                .collect();
-            let msg = InternalMessages::UserRequestBulkResponse {
-                users: list,
-                requester_id,
-                nonce,
-            };
-            let _ = tx.send(msg);
-        }
         _ => {}
     };
     Ok(())
@@ -0,0 +1,16 @@
+//! Codes sent to clients in websocket close frames.
+
+/// The server is shutting down or restarting
+pub const GOING_AWAY: u16 = 1001;
+/// The client stopped answering pings
+pub const HEARTBEAT_TIMEOUT: u16 = 4000;
+/// The client didn't send any messages for too long
+pub const IDLE_TIMEOUT: u16 = 4001;
+/// The client didn't read its messages fast enough and one that can't be skipped didn't fit in its queue
+pub const TOO_SLOW: u16 = 4002;
+/// An admin kicked the player, the reason is the one they gave
+pub const KICKED: u16 = 4003;
+/// The player is banned, sent when connecting and when the ban is made
+pub const BANNED: u16 = 4004;
+/// The client kept going over the rate limits
+pub const RATE_LIMITED: u16 = 4005;
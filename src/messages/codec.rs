@@ -0,0 +1,92 @@
+use axum::extract::ws::Message;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{invalid_message, nonce_of, parse_ws_message, to_ws_message, Messages};
+
+/// Encoding used for frames after the `/connect` handshake, which is always json
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+impl Codec {
+    /// Text frames are always parsed as json so clients can fall back to it.
+    pub fn decode(self, msg: &Message) -> Option<Messages> {
+        let data = match msg {
+            Message::Text(text) => return parse_ws_message(text),
+            Message::Binary(data) => data,
+            _ => return None,
+        };
+        match self.deserialize::<Messages>(data) {
+            Ok(msg) => Some(msg),
+            Err(error) => {
+                tracing::error!("Error parsing message: {}", error);
+                let nonce = self.deserialize(data).ok().as_ref().and_then(nonce_of);
+                Some(invalid_message(error, nonce))
+            }
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(self, data: &[u8]) -> Result<T, String> {
+        match self {
+            Codec::Json => serde_json::from_slice(data).map_err(|e| e.to_string()),
+            Codec::Msgpack => rmp_serde::from_slice(data).map_err(|e| e.to_string()),
+            Codec::Cbor => ciborium::de::from_reader(data).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn encode(self, msg: Messages) -> Message {
+        let data = match self {
+            Codec::Json => return to_ws_message(msg),
+            Codec::Msgpack => rmp_serde::to_vec_named(&msg).map_err(|e| e.to_string()),
+            Codec::Cbor => {
+                let mut data = Vec::new();
+                ciborium::ser::into_writer(&msg, &mut data)
+                    .map(|_| data)
+                    .map_err(|e| e.to_string())
+            }
+        };
+        match data {
+            Ok(data) => Message::Binary(data),
+            Err(e) => {
+                tracing::error!("Error encoding message: {}", e);
+                Message::Binary(Vec::new())
+            }
+        }
+    }
+}
+
+#[test]
+fn binary_codecs_round_trip() {
+    let uuid = uuid::Uuid::new_v4();
+    let msgs = [
+        Messages::IsOnlineBulk {
+            uuids: vec![uuid],
+            nonce: Some("HI!".to_owned()),
+        },
+        Messages::PresenceSubscribed {
+            users: [(uuid, true)].into_iter().collect(),
+            nonce: None,
+        },
+        Messages::IrcCreated {
+            message: "hello".to_owned(),
+            sender: uuid,
+            date: 1668109163235,
+            channel: "global".to_owned(),
+            id: 1,
+        },
+        Messages::Ping(None),
+        Messages::CosmeticAck,
+    ];
+    for codec in [Codec::Json, Codec::Msgpack, Codec::Cbor] {
+        for msg in msgs.iter().cloned() {
+            let json = serde_json::to_value(&msg).unwrap();
+            let decoded = codec.decode(&codec.encode(msg)).unwrap();
+            assert_eq!(serde_json::to_value(&decoded).unwrap(), json, "{:?}", codec);
+        }
+    }
+}
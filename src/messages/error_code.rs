@@ -0,0 +1,33 @@
+//! Codes sent to clients in `/error` messages, clients should match on these instead of the error message.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message could not be parsed, `details` has the reason
+    InvalidMessage,
+    /// The message needs a capability that was not negotiated, `details` has the capability
+    CapabilityNotNegotiated,
+    /// `/connect` was sent after the connection was already connected
+    AlreadyConnected,
+    /// The resume token is unknown, expired or already used
+    InvalidResumeToken,
+    /// The cosmetic, channel, friend request, friend or party invite does not exist
+    NotFound,
+    /// The user is missing the flags for a cosmetic or channel or isn't the party leader
+    Forbidden,
+    /// The user has to be in the irc channel or a party first
+    NotMember,
+    /// The request conflicts with the current state, like befriending a friend or creating a second party
+    Conflict,
+    /// A limit like the presence subscription limit, friend limit or party size was reached
+    LimitReached,
+    /// The message was rejected because of a rate limit, `retry_after` has the milliseconds until it's allowed again
+    RateLimited,
+    /// The player was muted for going over the rate limits, `retry_after` has the milliseconds until the mute ends
+    Muted,
+}
+
+/// Why a request was refused, the code and a message for the user
+pub type Rejection = (ErrorCode, &'static str);
@@ -4,34 +4,32 @@ use serde::{Deserialize, Serialize};
 
 use uuid::Uuid;
 
+use super::{DmStatus, ErrorCode, Rejection};
+use crate::{
+    app_state::Cosmetic,
+    cosmetic_log::CosmeticDelta,
+    friends::FriendStatus,
+    irc::history::IrcMessage,
+    party::Party,
+    presence::{Status, StatusPrivacy},
+};
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum InternalMessages {
-    RequestUser {
-        user_id: Uuid,
-        requester_id: Uuid,
-        nonce: Option<String>,
-    },
-    RequestUsersBulk {
-        user_ids: Vec<Uuid>,
-        requester_id: Uuid,
-        nonce: Option<String>,
-    },
     UserRequestResponse {
         requester_id: Uuid,
         is_online: bool,
         user_id: Uuid,
+        status: Option<Status>,
         nonce: Option<String>,
     },
     UserRequestBulkResponse {
         requester_id: Uuid,
         users: HashMap<Uuid, bool>,
+        statuses: Option<HashMap<Uuid, Status>>,
         nonce: Option<String>,
     },
-    UserInvalidJson {
-        requester_id: Uuid,
-        error: String,
-    },
     BroadCastMessage {
         // Minecraft Chat Codes
         message: String,
@@ -48,12 +46,129 @@ pub enum InternalMessages {
     },
     UserError {
         requester_id: Uuid,
+        code: ErrorCode,
         error: String,
+        details: Option<String>,
+        retry_after: Option<u64>,
         nonce: Option<String>,
     },
     IrcCreate {
         message: String,
         sender: Uuid,
-        date: u128,
+        date: u64,
+        channel: String,
+        id: u64,
+    },
+    IrcHistory {
+        requester_id: Uuid,
+        channel: String,
+        messages: Vec<IrcMessage>,
+        nonce: Option<String>,
+    },
+    /// Reply to joining or leaving an irc channel
+    IrcMembership {
+        requester_id: Uuid,
+        channel: String,
+        joined: bool,
+        nonce: Option<String>,
+    },
+    /// Tells every client to refetch cosmetics
+    CosmeticsAck,
+    /// A direct message, only the sessions of `to` receive it
+    Dm {
+        message: String,
+        sender: Uuid,
+        to: Uuid,
+        date: u64,
+    },
+    DmStatus {
+        requester_id: Uuid,
+        to: Uuid,
+        status: DmStatus,
+        nonce: Option<String>,
+    },
+    /// A friendship changed, sent to both users
+    FriendsUpdated {
+        requester_id: Uuid,
+        uuid: Uuid,
+        status: FriendStatus,
+        nonce: Option<String>,
+    },
+    FriendsList {
+        requester_id: Uuid,
+        friends: HashMap<Uuid, bool>,
+        requests: Vec<Uuid>,
+        nonce: Option<String>,
     },
+    /// A party changed, the nonce is only for the requester
+    PartyUpdated {
+        to: Vec<Uuid>,
+        requester_id: Uuid,
+        party: Option<Party>,
+        nonce: Option<String>,
+    },
+    PartyInvited {
+        to: Uuid,
+        party: Uuid,
+        from: Uuid,
+    },
+    PartyChat {
+        to: Vec<Uuid>,
+        message: String,
+        sender: Uuid,
+        date: u64,
+    },
+    /// Sent to every session that negotiated the cosmetics capability
+    CosmeticsDelta {
+        delta: CosmeticDelta,
+    },
+    CosmeticsSync {
+        requester_id: Uuid,
+        version: u64,
+        cosmetics: Option<Vec<Cosmetic>>,
+        users: Option<HashMap<Uuid, u8>>,
+        changes: Option<Vec<CosmeticDelta>>,
+        nonce: Option<String>,
+    },
+    StatusUpdated {
+        requester_id: Uuid,
+        status: Option<Status>,
+        privacy: StatusPrivacy,
+        nonce: Option<String>,
+    },
+    /// Closes the connections of a player with a close code and reason, never sent to the client as a message
+    Kick {
+        uuid: Uuid,
+        code: u16,
+        reason: String,
+    },
+    /// A user came online or went offline
+    PresenceUpdate {
+        uuid: Uuid,
+        online: bool,
+    },
+    PresenceSubscribed {
+        requester_id: Uuid,
+        users: HashMap<Uuid, bool>,
+        nonce: Option<String>,
+    },
+    PresenceUnsubscribed {
+        requester_id: Uuid,
+        uuids: Vec<Uuid>,
+        nonce: Option<String>,
+    },
+}
+
+impl InternalMessages {
+    /// An error for the requester without details.
+    pub fn user_error(requester_id: Uuid, (code, error): Rejection, nonce: Option<String>) -> Self {
+        InternalMessages::UserError {
+            requester_id,
+            code,
+            error: error.to_owned(),
+            details: None,
+            retry_after: None,
+            nonce,
+        }
+    }
 }
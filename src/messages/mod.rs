@@ -1,25 +1,43 @@
 use axum::extract::ws::Message;
 
+pub use codec::Codec;
+pub use error_code::{ErrorCode, Rejection};
 pub use internal_messages::InternalMessages;
-pub use websocket_messages::Messages;
+pub use websocket_messages::{DmStatus, Messages};
 
+pub mod close_code;
+mod codec;
+mod error_code;
 mod internal_messages;
+pub mod protocol;
 mod websocket_messages;
 
 pub fn parse_ws_message(msg: &str) -> Option<Messages> {
-    let msg = serde_json::from_str::<Messages>(msg);
-    match msg {
+    match serde_json::from_str::<Messages>(msg) {
         Ok(msg) => Some(msg),
         Err(e) => {
             tracing::error!("Error parsing message: {}", e);
-            Some(Messages::Error {
-                error: e.to_string(),
-                nonce: None,
-            })
+            let nonce = serde_json::from_str(msg).ok().as_ref().and_then(nonce_of);
+            Some(invalid_message(e.to_string(), nonce))
         }
     }
 }
 
+/// The nonce of a message that may not be valid, so messages that can't be handled can still be answered.
+fn nonce_of(value: &serde_json::Value) -> Option<String> {
+    value.get("c")?.get("nonce")?.as_str().map(ToOwned::to_owned)
+}
+
+fn invalid_message(details: String, nonce: Option<String>) -> Messages {
+    Messages::Error {
+        code: ErrorCode::InvalidMessage,
+        error: "Invalid message".to_owned(),
+        details: Some(details),
+        retry_after: None,
+        nonce,
+    }
+}
+
 pub fn to_ws_message(msg: Messages) -> Message {
     let msg = serde_json::to_string(&msg);
     match msg {
@@ -30,3 +48,20 @@ pub fn to_ws_message(msg: Messages) -> Message {
         }
     }
 }
+
+#[test]
+fn parse_errors_keep_the_nonce() {
+    let nonce = |msg: &str| match parse_ws_message(msg) {
+        Some(Messages::Error { code, nonce, .. }) => {
+            assert_eq!(code, ErrorCode::InvalidMessage);
+            nonce
+        }
+        msg => panic!("expected an error, got {:?}", msg),
+    };
+    assert_eq!(
+        nonce(r#"{"t":"/is_online","c":{"uuid":"nope","nonce":"HI!"}}"#).as_deref(),
+        Some("HI!")
+    );
+    assert_eq!(nonce(r#"{"t":"/unknown","c":{"nonce":"HI!"}}"#).as_deref(), Some("HI!"));
+    assert_eq!(nonce("not json"), None);
+}
@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+
+use crate::config::CONFIG;
+
+/// Bumped whenever a message changes in a way older clients can't handle
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const IRC_MESSAGES_PER_MINUTE: u32 = 4;
+pub const DM_MESSAGES_PER_MINUTE: u32 = 20;
+/// Maximum length in characters of the text fields of a status
+pub const STATUS_FIELD_LENGTH: usize = 32;
+
+/// Optional parts of the protocol, clients only receive and may only send messages of capabilities both sides
+/// agreed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// `/presence/*` subscriptions
+    Presence,
+    /// `/resume` after a disconnect
+    Resume,
+    /// `/dm/*` direct messages
+    Dm,
+    /// `/irc/join` and `/irc/leave` for named irc channels
+    Channels,
+    /// `/irc/history`
+    IrcHistory,
+    /// `/friends/*` and presence updates for friends
+    Friends,
+    /// `/party/*`
+    Parties,
+    /// `/cosmetics/sync` and `/cosmetics/delta`
+    Cosmetics,
+    /// `/status/set` and statuses in `/is_online` responses
+    Status,
+    /// Capabilities of newer clients this server doesn't know about
+    #[serde(other)]
+    Unknown,
+}
+
+impl Capability {
+    pub const SUPPORTED: &'static [Capability] = &[
+        Capability::Presence,
+        Capability::Resume,
+        Capability::Dm,
+        Capability::Channels,
+        Capability::IrcHistory,
+        Capability::Friends,
+        Capability::Parties,
+        Capability::Cosmetics,
+        Capability::Status,
+    ];
+}
+
+/// Capabilities the server and client both support.
+pub fn negotiate(requested: &[Capability]) -> Vec<Capability> {
+    Capability::SUPPORTED
+        .iter()
+        .copied()
+        .filter(|c| requested.contains(c))
+        .collect()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Limits {
+    pub messages_per_minute: u32,
+    pub irc_messages_per_minute: u32,
+    pub dm_messages_per_minute: u32,
+    pub presence_subscriptions: usize,
+    pub status_field_length: usize,
+}
+
+impl Limits {
+    pub fn current() -> Self {
+        Self {
+            messages_per_minute: CONFIG.ratelimit_per_minute.burst_size().get(),
+            irc_messages_per_minute: IRC_MESSAGES_PER_MINUTE,
+            dm_messages_per_minute: DM_MESSAGES_PER_MINUTE,
+            presence_subscriptions: CONFIG.presence_subscription_limit,
+            status_field_length: STATUS_FIELD_LENGTH,
+        }
+    }
+}
+
+/// Clients that don't send a protocol version get a bare `true`
+#[skip_serializing_none]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Connected {
+    Legacy(bool),
+    Negotiated {
+        version: u32,
+        features: Vec<Capability>,
+        limits: Limits,
+        resume_token: Option<String>,
+    },
+}
+
+#[test]
+fn negotiates_known_capabilities() {
+    let requested: Vec<Capability> = serde_json::from_str(r#"["presence", "time_travel", "presence"]"#).unwrap();
+    assert_eq!(negotiate(&requested), vec![Capability::Presence]);
+    assert_eq!(negotiate(&[]), vec![]);
+}
@@ -4,6 +4,20 @@ use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
 use uuid::Uuid;
 
+use crate::{
+    app_state::Cosmetic,
+    cosmetic_log::CosmeticDelta,
+    friends::FriendStatus,
+    irc::{global_channel, history::IrcMessage},
+    party::Party,
+    presence::{Status, StatusPrivacy},
+};
+
+use super::{
+    protocol::{Capability, Connected},
+    Codec, ErrorCode,
+};
+
 #[skip_serializing_none]
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "t", content = "c")]
@@ -13,22 +27,49 @@ pub enum Messages {
     #[serde(rename = "/is_online/bulk")]
     IsOnlineBulk { uuids: Vec<Uuid>, nonce: Option<String> },
     #[serde(rename = "/connect")]
-    Connect { server_id: String, username: String },
+    Connect {
+        server_id: String,
+        username: String,
+        #[serde(default)]
+        codec: Codec,
+        protocol: Option<u32>,
+        mod_name: Option<String>,
+        mod_version: Option<String>,
+        #[serde(default)]
+        capabilities: Vec<Capability>,
+    },
     #[serde(rename = "/is_online")]
     IsOnlineResponse {
         is_online: bool,
         uuid: Uuid,
+        status: Option<Status>,
         nonce: Option<String>,
     },
     #[serde(rename = "/is_online/bulk")]
     IsOnlineBulkResponse {
         users: HashMap<Uuid, bool>,
+        statuses: Option<HashMap<Uuid, Status>>,
         nonce: Option<String>,
     },
     #[serde(rename = "/connected")]
-    ConnectedResponse(bool),
+    ConnectedResponse(Connected),
+    #[serde(rename = "/resume")]
+    Resume { token: String },
+    #[serde(rename = "/resumed")]
+    Resumed {
+        resume_token: Option<String>,
+        missed: usize,
+        complete: bool,
+    },
     #[serde(rename = "/error")]
-    Error { error: String, nonce: Option<String> },
+    Error {
+        code: ErrorCode,
+        error: String,
+        details: Option<String>,
+        /// Milliseconds until the request is allowed again
+        retry_after: Option<u64>,
+        nonce: Option<String>,
+    },
     #[serde(rename = "/broadcast")]
     Broadcast(String),
     #[serde(rename = "/ping")]
@@ -47,8 +88,236 @@ pub enum Messages {
     },
     #[serde(rename = "/cosmetics/ack")]
     CosmeticAck,
+    /// Without `since` or when it is too old the response is a snapshot instead of changes
+    #[serde(rename = "/cosmetics/sync")]
+    CosmeticsSync { since: Option<u64>, nonce: Option<String> },
+    #[serde(rename = "/cosmetics/sync")]
+    CosmeticsSyncResponse {
+        version: u64,
+        cosmetics: Option<Vec<Cosmetic>>,
+        users: Option<HashMap<Uuid, u8>>,
+        changes: Option<Vec<CosmeticDelta>>,
+        nonce: Option<String>,
+    },
+    #[serde(rename = "/cosmetics/delta")]
+    CosmeticsDelta(CosmeticDelta),
     #[serde(rename = "/irc/create")]
-    IrcCreate { message: String },
+    IrcCreate {
+        message: String,
+        #[serde(default = "global_channel")]
+        channel: String,
+    },
     #[serde(rename = "/irc/created")]
-    IrcCreated { message: String, sender: Uuid, date: u128 },
+    IrcCreated {
+        message: String,
+        sender: Uuid,
+        date: u64,
+        channel: String,
+        id: u64,
+    },
+    #[serde(rename = "/irc/history")]
+    IrcHistory {
+        channel: String,
+        /// Only messages with a lower id
+        before: Option<u64>,
+        limit: Option<usize>,
+        nonce: Option<String>,
+    },
+    #[serde(rename = "/irc/history")]
+    IrcHistoryResponse {
+        channel: String,
+        messages: Vec<IrcMessage>,
+        nonce: Option<String>,
+    },
+    #[serde(rename = "/irc/join")]
+    IrcJoin { channel: String, nonce: Option<String> },
+    #[serde(rename = "/irc/joined")]
+    IrcJoined { channel: String, nonce: Option<String> },
+    #[serde(rename = "/irc/leave")]
+    IrcLeave { channel: String, nonce: Option<String> },
+    #[serde(rename = "/irc/left")]
+    IrcLeft { channel: String, nonce: Option<String> },
+    #[serde(rename = "/dm/send")]
+    DmSend {
+        to: Uuid,
+        message: String,
+        nonce: Option<String>,
+    },
+    #[serde(rename = "/dm/sent")]
+    DmSent {
+        to: Uuid,
+        status: DmStatus,
+        nonce: Option<String>,
+    },
+    #[serde(rename = "/dm/received")]
+    DmReceived { message: String, sender: Uuid, date: u64 },
+    #[serde(rename = "/friends/request")]
+    FriendsRequest { uuid: Uuid, nonce: Option<String> },
+    #[serde(rename = "/friends/accept")]
+    FriendsAccept { uuid: Uuid, nonce: Option<String> },
+    #[serde(rename = "/friends/decline")]
+    FriendsDecline { uuid: Uuid, nonce: Option<String> },
+    #[serde(rename = "/friends/remove")]
+    FriendsRemove { uuid: Uuid, nonce: Option<String> },
+    #[serde(rename = "/friends/updated")]
+    FriendsUpdated {
+        uuid: Uuid,
+        status: FriendStatus,
+        nonce: Option<String>,
+    },
+    #[serde(rename = "/friends/list")]
+    FriendsList { nonce: Option<String> },
+    #[serde(rename = "/friends/list")]
+    FriendsListResponse {
+        friends: HashMap<Uuid, bool>,
+        requests: Vec<Uuid>,
+        nonce: Option<String>,
+    },
+    #[serde(rename = "/party/create")]
+    PartyCreate { nonce: Option<String> },
+    #[serde(rename = "/party/invite")]
+    PartyInvite { uuid: Uuid, nonce: Option<String> },
+    #[serde(rename = "/party/accept")]
+    PartyAccept { party: Uuid, nonce: Option<String> },
+    #[serde(rename = "/party/leave")]
+    PartyLeave { nonce: Option<String> },
+    #[serde(rename = "/party/kick")]
+    PartyKick { uuid: Uuid, nonce: Option<String> },
+    #[serde(rename = "/party/transfer")]
+    PartyTransfer { uuid: Uuid, nonce: Option<String> },
+    #[serde(rename = "/party/chat")]
+    PartyChat { message: String },
+    #[serde(rename = "/party/updated")]
+    PartyUpdated {
+        party: Option<Party>,
+        nonce: Option<String>,
+    },
+    #[serde(rename = "/party/invited")]
+    PartyInvited { party: Uuid, from: Uuid },
+    #[serde(rename = "/party/message")]
+    PartyMessage { message: String, sender: Uuid, date: u64 },
+    #[serde(rename = "/status/set")]
+    StatusSet {
+        status: Option<Status>,
+        privacy: Option<StatusPrivacy>,
+        nonce: Option<String>,
+    },
+    #[serde(rename = "/status/updated")]
+    StatusUpdated {
+        status: Option<Status>,
+        privacy: StatusPrivacy,
+        nonce: Option<String>,
+    },
+    #[serde(rename = "/presence/subscribe")]
+    PresenceSubscribe { uuids: Vec<Uuid>, nonce: Option<String> },
+    #[serde(rename = "/presence/subscribed")]
+    PresenceSubscribed {
+        users: HashMap<Uuid, bool>,
+        nonce: Option<String>,
+    },
+    #[serde(rename = "/presence/unsubscribe")]
+    PresenceUnsubscribe { uuids: Vec<Uuid>, nonce: Option<String> },
+    #[serde(rename = "/presence/unsubscribed")]
+    PresenceUnsubscribed { uuids: Vec<Uuid>, nonce: Option<String> },
+    #[serde(rename = "/presence/update")]
+    PresenceUpdate { uuid: Uuid, online: bool },
+}
+
+/// What happened to a direct message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DmStatus {
+    /// The recipient has at least one session that negotiated `dm`
+    Delivered,
+    /// The recipient has no session that can receive it, the message is dropped
+    Offline,
+    /// The sender is blacklisted or sending too fast
+    Rejected,
+}
+
+impl Messages {
+    /// The nonce of any message that has one.
+    pub fn nonce(&self) -> Option<String> {
+        match self {
+            Messages::IsOnline { nonce, .. }
+            | Messages::IsOnlineBulk { nonce, .. }
+            | Messages::IsOnlineResponse { nonce, .. }
+            | Messages::IsOnlineBulkResponse { nonce, .. }
+            | Messages::Error { nonce, .. }
+            | Messages::CosmeticsUpdate { nonce, .. }
+            | Messages::CosmeticsUpdated { nonce, .. }
+            | Messages::CosmeticsSync { nonce, .. }
+            | Messages::CosmeticsSyncResponse { nonce, .. }
+            | Messages::IrcHistory { nonce, .. }
+            | Messages::IrcHistoryResponse { nonce, .. }
+            | Messages::IrcJoin { nonce, .. }
+            | Messages::IrcJoined { nonce, .. }
+            | Messages::IrcLeave { nonce, .. }
+            | Messages::IrcLeft { nonce, .. }
+            | Messages::DmSend { nonce, .. }
+            | Messages::DmSent { nonce, .. }
+            | Messages::FriendsRequest { nonce, .. }
+            | Messages::FriendsAccept { nonce, .. }
+            | Messages::FriendsDecline { nonce, .. }
+            | Messages::FriendsRemove { nonce, .. }
+            | Messages::FriendsUpdated { nonce, .. }
+            | Messages::FriendsList { nonce, .. }
+            | Messages::FriendsListResponse { nonce, .. }
+            | Messages::PartyCreate { nonce, .. }
+            | Messages::PartyInvite { nonce, .. }
+            | Messages::PartyAccept { nonce, .. }
+            | Messages::PartyLeave { nonce, .. }
+            | Messages::PartyKick { nonce, .. }
+            | Messages::PartyTransfer { nonce, .. }
+            | Messages::PartyUpdated { nonce, .. }
+            | Messages::StatusSet { nonce, .. }
+            | Messages::StatusUpdated { nonce, .. }
+            | Messages::PresenceSubscribe { nonce, .. }
+            | Messages::PresenceSubscribed { nonce, .. }
+            | Messages::PresenceUnsubscribe { nonce, .. }
+            | Messages::PresenceUnsubscribed { nonce, .. } => nonce.clone(),
+            _ => None,
+        }
+    }
+
+    /// Capability a client has to negotiate before sending this message.
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            Messages::PresenceSubscribe { .. } | Messages::PresenceUnsubscribe { .. } => Some(Capability::Presence),
+            Messages::DmSend { .. } => Some(Capability::Dm),
+            Messages::IrcJoin { .. } | Messages::IrcLeave { .. } => Some(Capability::Channels),
+            Messages::IrcHistory { .. } => Some(Capability::IrcHistory),
+            Messages::FriendsRequest { .. }
+            | Messages::FriendsAccept { .. }
+            | Messages::FriendsDecline { .. }
+            | Messages::FriendsRemove { .. }
+            | Messages::FriendsList { .. } => Some(Capability::Friends),
+            Messages::CosmeticsSync { .. } => Some(Capability::Cosmetics),
+            Messages::StatusSet { .. } => Some(Capability::Status),
+            Messages::PartyCreate { .. }
+            | Messages::PartyInvite { .. }
+            | Messages::PartyAccept { .. }
+            | Messages::PartyLeave { .. }
+            | Messages::PartyKick { .. }
+            | Messages::PartyTransfer { .. }
+            | Messages::PartyChat { .. } => Some(Capability::Parties),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn nonces_are_read_from_the_message() {
+    let with_nonce = Messages::FriendsList {
+        nonce: Some("a".to_owned()),
+    };
+    assert_eq!(with_nonce.nonce().as_deref(), Some("a"));
+    assert_eq!(
+        Messages::PartyChat {
+            message: "hi".to_owned()
+        }
+        .nonce(),
+        None
+    );
+    assert_eq!(Messages::Ping(Some("a".to_owned())).nonce(), None);
 }
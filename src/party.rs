@@ -0,0 +1,177 @@
+use std::collections::{HashMap, HashSet};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::messages::{ErrorCode, Rejection};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Party {
+    pub id: Uuid,
+    pub leader: Uuid,
+    /// In the order they joined, the leader included
+    pub members: Vec<Uuid>,
+    #[serde(skip)]
+    pub invites: HashSet<Uuid>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    parties: HashMap<Uuid, Party>,
+    /// Party of every player that is in one
+    members: HashMap<Uuid, Uuid>,
+}
+
+/// Parties only live in memory, players leave their party when they go offline.
+#[derive(Debug, Default)]
+pub struct Parties {
+    inner: Mutex<Inner>,
+}
+
+impl Inner {
+    fn party_of(&mut self, player: Uuid) -> Result<&mut Party, Rejection> {
+        let id = self
+            .members
+            .get(&player)
+            .ok_or((ErrorCode::NotMember, "You are not in a party"))?;
+        Ok(self.parties.get_mut(id).unwrap())
+    }
+
+    fn led_by(&mut self, player: Uuid) -> Result<&mut Party, Rejection> {
+        let party = self.party_of(player)?;
+        if party.leader != player {
+            return Err((ErrorCode::Forbidden, "You are not the party leader"));
+        }
+        Ok(party)
+    }
+}
+
+impl Parties {
+    pub fn create(&self, leader: Uuid) -> Result<Party, Rejection> {
+        let mut inner = self.inner.lock();
+        if inner.members.contains_key(&leader) {
+            return Err((ErrorCode::Conflict, "You are already in a party"));
+        }
+        let party = Party {
+            id: Uuid::new_v4(),
+            leader,
+            members: vec![leader],
+            invites: HashSet::new(),
+        };
+        inner.members.insert(leader, party.id);
+        inner.parties.insert(party.id, party.clone());
+        Ok(party)
+    }
+
+    pub fn invite(&self, leader: Uuid, player: Uuid, size: usize) -> Result<Party, Rejection> {
+        let mut inner = self.inner.lock();
+        let party = inner.led_by(leader)?;
+        if party.members.contains(&player) {
+            return Err((ErrorCode::Conflict, "This player is already in your party"));
+        }
+        if party.members.len() >= size {
+            return Err((ErrorCode::LimitReached, "Your party is full"));
+        }
+        party.invites.insert(player);
+        Ok(party.clone())
+    }
+
+    pub fn accept(&self, player: Uuid, id: Uuid, size: usize) -> Result<Party, Rejection> {
+        let mut inner = self.inner.lock();
+        if inner.members.contains_key(&player) {
+            return Err((ErrorCode::Conflict, "You are already in a party"));
+        }
+        let party = inner
+            .parties
+            .get_mut(&id)
+            .filter(|p| p.invites.contains(&player))
+            .ok_or((ErrorCode::NotFound, "You are not invited to this party"))?;
+        if party.members.len() >= size {
+            return Err((ErrorCode::LimitReached, "This party is full"));
+        }
+        party.invites.remove(&player);
+        party.members.push(player);
+        let party = party.clone();
+        inner.members.insert(player, id);
+        Ok(party)
+    }
+
+    /// Removes a player from their party, None if the party was disbanded because it is empty now.
+    pub fn leave(&self, player: Uuid) -> Result<Option<Party>, Rejection> {
+        let mut inner = self.inner.lock();
+        let party = inner.party_of(player)?;
+        party.members.retain(|m| *m != player);
+        let party = match party.members.first() {
+            Some(next) => {
+                if party.leader == player {
+                    party.leader = *next;
+                }
+                Some(party.clone())
+            }
+            None => {
+                let id = party.id;
+                inner.parties.remove(&id);
+                None
+            }
+        };
+        inner.members.remove(&player);
+        Ok(party)
+    }
+
+    pub fn kick(&self, leader: Uuid, player: Uuid) -> Result<Party, Rejection> {
+        let mut inner = self.inner.lock();
+        let party = inner.led_by(leader)?;
+        if player == leader || !party.members.contains(&player) {
+            return Err((ErrorCode::NotFound, "This player is not in your party"));
+        }
+        party.members.retain(|m| *m != player);
+        let party = party.clone();
+        inner.members.remove(&player);
+        Ok(party)
+    }
+
+    pub fn transfer(&self, leader: Uuid, player: Uuid) -> Result<Party, Rejection> {
+        let mut inner = self.inner.lock();
+        let party = inner.led_by(leader)?;
+        if !party.members.contains(&player) {
+            return Err((ErrorCode::NotFound, "This player is not in your party"));
+        }
+        party.leader = player;
+        Ok(party.clone())
+    }
+
+    pub fn get(&self, player: Uuid) -> Option<Party> {
+        let mut inner = self.inner.lock();
+        inner.party_of(player).ok().cloned()
+    }
+
+    pub fn count(&self) -> usize {
+        self.inner.lock().parties.len()
+    }
+}
+
+#[test]
+fn party_lifecycle() {
+    let parties = Parties::default();
+    let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let party = parties.create(a).unwrap();
+    assert!(parties.create(a).is_err());
+    assert!(parties.accept(b, party.id, 5).is_err());
+
+    parties.invite(a, b, 5).unwrap();
+    assert!(parties.invite(b, c, 5).is_err());
+    assert_eq!(parties.accept(b, party.id, 5).unwrap().members, [a, b]);
+
+    parties.invite(a, c, 5).unwrap();
+    assert!(parties.accept(c, party.id, 2).is_err());
+
+    assert_eq!(parties.transfer(a, b).unwrap().leader, b);
+    assert!(parties.kick(a, b).is_err());
+    assert_eq!(parties.kick(b, a).unwrap().members, [b]);
+    assert!(parties.get(a).is_none());
+
+    assert_eq!(parties.leave(b).unwrap(), None);
+    assert_eq!(parties.count(), 0);
+    assert!(parties.leave(b).is_err());
+}
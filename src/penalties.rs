@@ -0,0 +1,99 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use uuid::Uuid;
+
+/// What happens to a client that went over a rate limit, on top of the message being rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Penalty {
+    Reject,
+    /// Muted in irc, party chat and direct messages for a while
+    Mute,
+    Disconnect,
+}
+
+/// The rate limit violations of one connection within the last `window`, every penalty is given once when its
+/// threshold is reached and a threshold of 0 disables it.
+#[derive(Debug)]
+pub struct Violations {
+    window: Duration,
+    mute_after: usize,
+    disconnect_after: usize,
+    times: VecDeque<Instant>,
+}
+
+impl Violations {
+    pub fn new(window: Duration, mute_after: usize, disconnect_after: usize) -> Self {
+        Self {
+            window,
+            mute_after,
+            disconnect_after,
+            times: VecDeque::new(),
+        }
+    }
+
+    /// Records a violation and gives the penalty for it.
+    pub fn record(&mut self, now: Instant) -> Penalty {
+        while matches!(self.times.front(), Some(t) if now.duration_since(*t) >= self.window) {
+            self.times.pop_front();
+        }
+        self.times.push_back(now);
+        let count = self.times.len();
+        if self.disconnect_after > 0 && count == self.disconnect_after {
+            Penalty::Disconnect
+        } else if self.mute_after > 0 && count == self.mute_after {
+            Penalty::Mute
+        } else {
+            Penalty::Reject
+        }
+    }
+}
+
+/// Players that can't chat until a moment, kept across reconnects but not restarts.
+#[derive(Debug, Default)]
+pub struct Mutes(Mutex<HashMap<Uuid, Instant>>);
+
+impl Mutes {
+    pub fn mute(&self, uuid: Uuid, until: Instant) {
+        self.0.lock().insert(uuid, until);
+    }
+
+    /// How long the player is still muted for, None when they aren't.
+    pub fn remaining(&self, uuid: &Uuid, now: Instant) -> Option<Duration> {
+        let mut mutes = self.0.lock();
+        let until = *mutes.get(uuid)?;
+        if until <= now {
+            mutes.remove(uuid);
+            return None;
+        }
+        Some(until - now)
+    }
+}
+
+#[test]
+fn violations_escalate_within_the_window() {
+    let mut violations = Violations::new(Duration::from_secs(60), 2, 4);
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+    assert_eq!(violations.record(at(0)), Penalty::Reject);
+    assert_eq!(violations.record(at(1)), Penalty::Mute);
+    assert_eq!(violations.record(at(2)), Penalty::Reject);
+    assert_eq!(violations.record(at(3)), Penalty::Disconnect);
+    assert_eq!(violations.record(at(4)), Penalty::Reject);
+    // The earlier ones are forgotten by now, so this is the second one again
+    assert_eq!(violations.record(at(70)), Penalty::Reject);
+    assert_eq!(violations.record(at(71)), Penalty::Mute);
+
+    let mut lenient = Violations::new(Duration::from_secs(60), 0, 0);
+    assert!((0..10).all(|s| lenient.record(at(s)) == Penalty::Reject));
+
+    let mutes = Mutes::default();
+    let uuid = Uuid::new_v4();
+    assert_eq!(mutes.remaining(&uuid, at(0)), None);
+    mutes.mute(uuid, at(30));
+    assert_eq!(mutes.remaining(&uuid, at(10)), Some(Duration::from_secs(20)));
+    assert_eq!(mutes.remaining(&uuid, at(30)), None);
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use uuid::Uuid;
+
+use crate::messages::protocol::STATUS_FIELD_LENGTH;
+
+/// What a player is doing, set by their client with `/status/set` and forgotten when they go offline
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Status {
+    /// Hypixel server, like `mini123A`
+    pub server: Option<String>,
+    /// Skyblock island or area
+    pub area: Option<String>,
+    #[serde(default)]
+    pub afk: bool,
+}
+
+impl Status {
+    pub fn is_valid(&self) -> bool {
+        [&self.server, &self.area]
+            .into_iter()
+            .flatten()
+            .all(|field| field.chars().count() <= STATUS_FIELD_LENGTH)
+    }
+}
+
+/// Who can see a player's status
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatusPrivacy {
+    #[default]
+    Everyone,
+    Friends,
+    Nobody,
+}
+
+impl StatusPrivacy {
+    pub fn is_everyone(&self) -> bool {
+        *self == StatusPrivacy::Everyone
+    }
+}
+
+/// Counts the live websocket sessions of every player, a player is online while they have at least one.
+#[derive(Debug, Default)]
+pub struct Presence {
+    sessions: Mutex<HashMap<Uuid, usize>>,
+    statuses: Mutex<HashMap<Uuid, Status>>,
+}
+
+impl Presence {
+    /// Registers a new session, returns true if the player just came online.
+    pub fn connect(&self, uuid: Uuid) -> bool {
+        let mut sessions = self.sessions.lock();
+        let count = sessions.entry(uuid).or_default();
+        *count += 1;
+        *count == 1
+    }
+
+    /// Removes a session, returns true if the player just went offline.
+    pub fn disconnect(&self, uuid: Uuid) -> bool {
+        let mut sessions = self.sessions.lock();
+        match sessions.get_mut(&uuid) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                sessions.remove(&uuid);
+                self.statuses.lock().remove(&uuid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_online(&self, uuid: &Uuid) -> bool {
+        self.sessions.lock().contains_key(uuid)
+    }
+
+    /// Sets the status of an online player, the last status any of their sessions set wins.
+    pub fn set_status(&self, uuid: Uuid, status: Status) {
+        if self.is_online(&uuid) {
+            self.statuses.lock().insert(uuid, status);
+        }
+    }
+
+    pub fn status(&self, uuid: &Uuid) -> Option<Status> {
+        self.statuses.lock().get(uuid).cloned()
+    }
+
+    /// Amount of players that are online.
+    pub fn online_count(&self) -> usize {
+        self.sessions.lock().len()
+    }
+
+    /// Amount of sessions, players with multiple clients open are counted more than once.
+    pub fn session_count(&self) -> usize {
+        self.sessions.lock().values().sum()
+    }
+}
+
+#[test]
+fn counts_sessions_per_player() {
+    let presence = Presence::default();
+    let uuid = Uuid::new_v4();
+    assert!(presence.connect(uuid));
+    assert!(!presence.connect(uuid));
+    assert_eq!((presence.online_count(), presence.session_count()), (1, 2));
+    assert!(!presence.disconnect(uuid));
+    assert!(presence.is_online(&uuid));
+    assert!(presence.disconnect(uuid));
+    assert!(!presence.is_online(&uuid));
+    assert!(!presence.disconnect(uuid));
+}
+
+#[test]
+fn status_is_forgotten_when_offline() {
+    let presence = Presence::default();
+    let uuid = Uuid::new_v4();
+    let status = Status {
+        server: Some("mini123A".to_owned()),
+        area: Some("Private Island".to_owned()),
+        afk: true,
+    };
+    assert!(status.is_valid());
+    assert!(!Status {
+        area: Some("a".repeat(STATUS_FIELD_LENGTH + 1)),
+        ..Default::default()
+    }
+    .is_valid());
+
+    presence.set_status(uuid, status.clone());
+    assert_eq!(presence.status(&uuid), None);
+    presence.connect(uuid);
+    presence.connect(uuid);
+    presence.set_status(uuid, status.clone());
+    presence.disconnect(uuid);
+    assert_eq!(presence.status(&uuid), Some(status));
+    presence.disconnect(uuid);
+    assert_eq!(presence.status(&uuid), None);
+}
@@ -0,0 +1,730 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::{
+    sync::{oneshot, Notify},
+    task::JoinHandle,
+    time::sleep,
+};
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    config::CONFIG,
+    irc::global_channel,
+    messages::{protocol::Capability, Codec, InternalMessages, Messages},
+};
+
+/// A connected player, kept around while their connection is parked so it can be resumed.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// Id of the connection in the [`SessionRegistry`]
+    pub id: u64,
+    pub uuid: Uuid,
+    pub codec: Codec,
+    pub features: Vec<Capability>,
+    /// Users this session wants presence updates for, mirrored in [`SessionRegistry`] to find the subscribers of a user
+    pub subscriptions: Arc<Mutex<HashSet<Uuid>>>,
+    /// Irc channels this session is in
+    pub channels: Arc<Mutex<HashSet<String>>>,
+    /// Token to resume this session with, None if it can't be resumed
+    pub token: Option<String>,
+}
+
+impl Session {
+    pub fn new(id: u64, uuid: Uuid, codec: Codec, features: Vec<Capability>) -> Self {
+        let mut session = Self {
+            id,
+            uuid,
+            codec,
+            features,
+            subscriptions: Default::default(),
+            channels: Arc::new(Mutex::new(HashSet::from([global_channel()]))),
+            token: None,
+        };
+        session.token = session.new_token();
+        session
+    }
+
+    /// A fresh resume token, tokens are single use so this is called again after every resume.
+    pub fn new_token(&self) -> Option<String> {
+        (self.features.contains(&Capability::Resume) && CONFIG.resume_window > 0)
+            .then(|| Uuid::new_v4().simple().to_string())
+    }
+
+    /// Turns an internal message into the message this session should receive, if any.
+    pub fn route(&self, msg: InternalMessages) -> Option<Messages> {
+        let uuid = self.uuid;
+        let has = |capability| self.features.contains(&capability);
+        let msg = match msg {
+            InternalMessages::UserError {
+                requester_id,
+                code,
+                error,
+                details,
+                retry_after,
+                nonce,
+            } if requester_id == uuid => Messages::Error {
+                code,
+                error,
+                details,
+                retry_after,
+                nonce,
+            },
+            InternalMessages::CosmeticsUpdate {
+                requester_id,
+                cosmetic_id,
+                nonce,
+            } => {
+                if requester_id == uuid {
+                    Messages::CosmeticsUpdated { cosmetic_id, nonce }
+                } else {
+                    Messages::CosmeticAck
+                }
+            }
+            InternalMessages::UserRequestResponse {
+                is_online,
+                requester_id,
+                user_id,
+                status,
+                nonce,
+            } if requester_id == uuid => Messages::IsOnlineResponse {
+                is_online,
+                uuid: user_id,
+                status,
+                nonce,
+            },
+            InternalMessages::UserRequestBulkResponse {
+                requester_id,
+                users,
+                statuses,
+                nonce,
+            } if requester_id == uuid => Messages::IsOnlineBulkResponse { users, statuses, nonce },
+            InternalMessages::StatusUpdated {
+                requester_id,
+                status,
+                privacy,
+                nonce,
+            } if requester_id == uuid => Messages::StatusUpdated { status, privacy, nonce },
+            InternalMessages::BroadCastMessage { message, to } if to.contains(&uuid) || to.is_empty() => {
+                Messages::Broadcast(message)
+            }
+            InternalMessages::Pong {
+                nonce,
+                uuid: requester_id,
+            } if requester_id == uuid => Messages::Pong(nonce),
+            InternalMessages::IrcCreate {
+                message,
+                sender,
+                date,
+                channel,
+                id,
+            } if self.channels.lock().contains(&channel) => Messages::IrcCreated {
+                message,
+                sender,
+                date,
+                channel,
+                id,
+            },
+            InternalMessages::IrcHistory {
+                requester_id,
+                channel,
+                messages,
+                nonce,
+            } if requester_id == uuid => Messages::IrcHistoryResponse {
+                channel,
+                messages,
+                nonce,
+            },
+            InternalMessages::IrcMembership {
+                requester_id,
+                channel,
+                joined,
+                nonce,
+            } if requester_id == uuid => {
+                if joined {
+                    Messages::IrcJoined { channel, nonce }
+                } else {
+                    Messages::IrcLeft { channel, nonce }
+                }
+            }
+            InternalMessages::CosmeticsAck => Messages::CosmeticAck,
+            InternalMessages::CosmeticsDelta { delta } if has(Capability::Cosmetics) => Messages::CosmeticsDelta(delta),
+            InternalMessages::CosmeticsSync {
+                requester_id,
+                version,
+                cosmetics,
+                users,
+                changes,
+                nonce,
+            } if requester_id == uuid => Messages::CosmeticsSyncResponse {
+                version,
+                cosmetics,
+                users,
+                changes,
+                nonce,
+            },
+            InternalMessages::Dm {
+                message,
+                sender,
+                to,
+                date,
+            } if to == uuid && has(Capability::Dm) => Messages::DmReceived { message, sender, date },
+            InternalMessages::DmStatus {
+                requester_id,
+                to,
+                status,
+                nonce,
+            } if requester_id == uuid => Messages::DmSent { to, status, nonce },
+            // Only sent to subscribers and friends by the registry
+            InternalMessages::PresenceUpdate { uuid: user, online } => Messages::PresenceUpdate { uuid: user, online },
+            InternalMessages::PartyUpdated {
+                to,
+                requester_id,
+                party,
+                nonce,
+            } if to.contains(&uuid) && has(Capability::Parties) => Messages::PartyUpdated {
+                party,
+                nonce: if requester_id == uuid { nonce } else { None },
+            },
+            InternalMessages::PartyInvited { to, party, from } if to == uuid && has(Capability::Parties) => {
+                Messages::PartyInvited { party, from }
+            }
+            InternalMessages::PartyChat {
+                to,
+                message,
+                sender,
+                date,
+            } if to.contains(&uuid) && has(Capability::Parties) => Messages::PartyMessage { message, sender, date },
+            InternalMessages::FriendsUpdated {
+                requester_id,
+                uuid: user,
+                status,
+                nonce,
+            } if requester_id == uuid && has(Capability::Friends) => Messages::FriendsUpdated {
+                uuid: user,
+                status,
+                nonce,
+            },
+            InternalMessages::FriendsList {
+                requester_id,
+                friends,
+                requests,
+                nonce,
+            } if requester_id == uuid => Messages::FriendsListResponse {
+                friends,
+                requests,
+                nonce,
+            },
+            InternalMessages::PresenceSubscribed {
+                requester_id,
+                users,
+                nonce,
+            } if requester_id == uuid => Messages::PresenceSubscribed { users, nonce },
+            InternalMessages::PresenceUnsubscribed {
+                requester_id,
+                uuids,
+                nonce,
+            } if requester_id == uuid => Messages::PresenceUnsubscribed { uuids, nonce },
+            _ => return None,
+        };
+        Some(msg)
+    }
+}
+
+/// What happened to a message given to an [`Outbox`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pushed {
+    Queued,
+    /// The message isn't meant for this connection
+    Ignored,
+    /// The queue was full and the message can be missed
+    Dropped,
+    /// The queue was full and the presence update will be sent after it, replacing older ones for the same user
+    Coalesced,
+    /// The queue was full with a message that can't be missed, the connection gets closed
+    TooSlow,
+}
+
+/// What the connection of an [`Outbox`] should do next.
+#[derive(Debug)]
+pub enum Outgoing {
+    Message(Messages),
+    /// Close the connection with this code and reason
+    Close {
+        code: u16,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    messages: VecDeque<Messages>,
+    /// Latest presence of users whose updates didn't fit in the queue, sent once it is empty
+    presence: HashMap<Uuid, bool>,
+    /// Set by a kick, which skips the line since it closes the connection anyway
+    close: Option<(u16, String)>,
+    too_slow: bool,
+}
+
+/// Bounded queue of messages for one connection, so a slow client can't make the server buffer without limit.
+///
+/// Messages are routed for the session when they are pushed so only what the client receives takes up space. When it
+/// is full irc messages, cosmetic acks and deltas are dropped since clients can catch up on those, presence updates
+/// of up to `capacity` users are coalesced and anything else closes the connection as too slow.
+#[derive(Debug)]
+pub struct Outbox {
+    session: Session,
+    capacity: usize,
+    queue: Mutex<Queue>,
+    notify: Notify,
+}
+
+impl Outbox {
+    fn new(session: Session, capacity: usize) -> Self {
+        Self {
+            session,
+            capacity,
+            queue: Default::default(),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn push(&self, msg: InternalMessages) -> Pushed {
+        let msg = match msg {
+            InternalMessages::Kick { code, reason, .. } => {
+                self.queue.lock().close = Some((code, reason));
+                self.notify.notify_one();
+                return Pushed::Queued;
+            }
+            msg => match self.session.route(msg) {
+                Some(msg) => msg,
+                None => return Pushed::Ignored,
+            },
+        };
+        let mut queue = self.queue.lock();
+        if queue.too_slow {
+            return Pushed::Dropped;
+        }
+        let full = queue.messages.len() >= self.capacity;
+        let pushed = match msg {
+            Messages::PresenceUpdate { uuid, online } if full && queue.presence.contains_key(&uuid) => {
+                queue.presence.insert(uuid, online);
+                Pushed::Coalesced
+            }
+            Messages::PresenceUpdate { uuid, online } if full && queue.presence.len() < self.capacity => {
+                queue.presence.insert(uuid, online);
+                Pushed::Coalesced
+            }
+            msg if !full => {
+                // A coalesced update for the same user would be sent later and overwrite this with older state
+                if let Messages::PresenceUpdate { uuid, .. } = &msg {
+                    queue.presence.remove(uuid);
+                }
+                queue.messages.push_back(msg);
+                Pushed::Queued
+            }
+            Messages::CosmeticAck | Messages::CosmeticsDelta(_) | Messages::IrcCreated { .. } => Pushed::Dropped,
+            _ => {
+                queue.too_slow = true;
+                Pushed::TooSlow
+            }
+        };
+        drop(queue);
+        self.notify.notify_one();
+        pushed
+    }
+
+    /// What to send next, None once the connection is too slow and should be closed.
+    pub async fn recv(&self) -> Option<Outgoing> {
+        loop {
+            {
+                let mut queue = self.queue.lock();
+                if let Some((code, reason)) = queue.close.take() {
+                    return Some(Outgoing::Close { code, reason });
+                }
+                if queue.too_slow {
+                    return None;
+                }
+                if let Some(msg) = queue.messages.pop_front() {
+                    return Some(Outgoing::Message(msg));
+                }
+                if let Some(&uuid) = queue.presence.keys().next() {
+                    let online = queue.presence.remove(&uuid).unwrap();
+                    return Some(Outgoing::Message(Messages::PresenceUpdate { uuid, online }));
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Amount of messages waiting to be sent.
+    pub fn queued(&self) -> usize {
+        let queue = self.queue.lock();
+        queue.messages.len() + queue.presence.len()
+    }
+}
+
+/// Outboxes of every connection, so messages for a player go straight to their connections instead of every
+/// connection filtering every message.
+#[derive(Debug)]
+pub struct SessionRegistry {
+    outboxes: Mutex<HashMap<Uuid, Vec<Arc<Outbox>>>>,
+    /// Connections subscribed to the presence of a user, by connection id
+    subscribers: Mutex<HashMap<Uuid, HashMap<u64, Arc<Outbox>>>>,
+    next_id: AtomicU64,
+    outbox_size: usize,
+    /// Messages dropped because an outbox was full
+    pub dropped: AtomicUsize,
+    /// Presence updates merged because an outbox was full
+    pub coalesced: AtomicUsize,
+}
+
+impl SessionRegistry {
+    pub fn new(outbox_size: usize) -> Self {
+        Self {
+            outboxes: Default::default(),
+            subscribers: Default::default(),
+            next_id: AtomicU64::new(0),
+            outbox_size,
+            dropped: AtomicUsize::new(0),
+            coalesced: AtomicUsize::new(0),
+        }
+    }
+
+    /// An id for a new connection.
+    pub fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Adds a connection of a player, returns where its messages arrive.
+    pub fn register(&self, session: &Session) -> Arc<Outbox> {
+        let outbox = Arc::new(Outbox::new(session.clone(), self.outbox_size));
+        self.outboxes
+            .lock()
+            .entry(session.uuid)
+            .or_default()
+            .push(outbox.clone());
+        outbox
+    }
+
+    pub fn unregister(&self, session: &Session) {
+        let subscriptions: Vec<Uuid> = session.subscriptions.lock().iter().copied().collect();
+        self.unsubscribe(session.id, &subscriptions);
+        let mut outboxes = self.outboxes.lock();
+        if let Some(connections) = outboxes.get_mut(&session.uuid) {
+            connections.retain(|o| o.session.id != session.id);
+            if connections.is_empty() {
+                outboxes.remove(&session.uuid);
+            }
+        }
+    }
+
+    /// Sends the presence updates of `users` to a connection, call this after adding them to its subscriptions.
+    pub fn subscribe(&self, session: &Session, users: &[Uuid]) {
+        let outbox = self
+            .outboxes
+            .lock()
+            .get(&session.uuid)
+            .and_then(|connections| connections.iter().find(|o| o.session.id == session.id).cloned());
+        if let Some(outbox) = outbox {
+            let mut subscribers = self.subscribers.lock();
+            for user in users {
+                subscribers.entry(*user).or_default().insert(session.id, outbox.clone());
+            }
+        }
+    }
+
+    pub fn unsubscribe(&self, id: u64, users: &[Uuid]) {
+        let mut subscribers = self.subscribers.lock();
+        for user in users {
+            if let Some(connections) = subscribers.get_mut(user) {
+                connections.remove(&id);
+                if connections.is_empty() {
+                    subscribers.remove(user);
+                }
+            }
+        }
+    }
+
+    fn push(&self, outbox: &Outbox, msg: InternalMessages) -> Pushed {
+        let pushed = outbox.push(msg);
+        match pushed {
+            Pushed::Dropped => self.dropped.fetch_add(1, Ordering::Relaxed),
+            Pushed::Coalesced => self.coalesced.fetch_add(1, Ordering::Relaxed),
+            Pushed::Queued | Pushed::Ignored | Pushed::TooSlow => 0,
+        };
+        pushed
+    }
+
+    /// Sends a message to every connection of a player, parked ones included. Returns false if none of them receive
+    /// it, because the player isn't connected or didn't negotiate what it needs.
+    pub fn send(&self, uuid: Uuid, msg: InternalMessages) -> bool {
+        let mut received = false;
+        if let Some(connections) = self.outboxes.lock().get(&uuid) {
+            for outbox in connections {
+                received |= self.push(outbox, msg.clone()) != Pushed::Ignored;
+            }
+        }
+        received
+    }
+
+    /// Sends a reply to the one connection that asked for it.
+    pub fn reply(&self, outbox: &Outbox, msg: InternalMessages) {
+        self.push(outbox, msg);
+    }
+
+    pub fn send_many(&self, uuids: &[Uuid], msg: InternalMessages) {
+        for uuid in uuids {
+            self.send(*uuid, msg.clone());
+        }
+    }
+
+    /// Sends a presence update to the connections subscribed to the player and to their friends that negotiated
+    /// `friends`, each connection gets it once.
+    pub fn send_presence(&self, uuid: Uuid, online: bool, friends: &HashSet<Uuid>) {
+        let msg = InternalMessages::PresenceUpdate { uuid, online };
+        let mut sent = HashSet::new();
+        if let Some(connections) = self.subscribers.lock().get(&uuid) {
+            for (id, outbox) in connections {
+                sent.insert(*id);
+                self.push(outbox, msg.clone());
+            }
+        }
+        let outboxes = self.outboxes.lock();
+        for outbox in friends.iter().filter_map(|friend| outboxes.get(friend)).flatten() {
+            if outbox.session.features.contains(&Capability::Friends) && !sent.contains(&outbox.session.id) {
+                self.push(outbox, msg.clone());
+            }
+        }
+    }
+
+    /// Sends a message to every connection, they decide themselves if it is meant for them.
+    pub fn broadcast(&self, msg: InternalMessages) {
+        for outbox in self.outboxes.lock().values().flatten() {
+            self.push(outbox, msg.clone());
+        }
+    }
+
+    /// Amount of registered connections.
+    pub fn count(&self) -> usize {
+        self.outboxes.lock().values().map(Vec::len).sum()
+    }
+
+    /// Amount of messages waiting in all outboxes.
+    pub fn queued(&self) -> usize {
+        self.outboxes.lock().values().flatten().map(|o| o.queued()).sum()
+    }
+}
+
+/// What a resumed connection picks up from the parked one.
+pub struct Resumed {
+    pub session: Session,
+    pub outbox: Arc<Outbox>,
+    pub missed: VecDeque<Messages>,
+    /// False if messages had to be dropped from the replay buffer
+    pub complete: bool,
+}
+
+struct Parked {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Option<Resumed>>,
+}
+
+/// Sessions of players that disconnected less than `--resume-window` seconds ago, keyed by resume token.
+#[derive(Default)]
+pub struct ResumableSessions {
+    parked: Mutex<HashMap<String, Parked>>,
+}
+
+impl ResumableSessions {
+    /// Keeps routing messages for a disconnected session into a replay buffer until it is resumed or the window
+    /// runs out, after which the player goes offline.
+    pub fn park(state: &Arc<AppState>, token: String, session: Session, outbox: Arc<Outbox>) {
+        let (stop, mut stopped) = oneshot::channel();
+        let state_clone = state.clone();
+        let token_clone = token.clone();
+        // Held until the entry is in, a task that ends right away (too slow outbox, shutdown) has to find it
+        let mut parked = state.resumable.parked.lock();
+        let task = tokio::spawn(async move {
+            let state = state_clone;
+            let mut missed = VecDeque::new();
+            let mut complete = true;
+            let expire = sleep(Duration::from_secs(CONFIG.resume_window));
+            tokio::pin!(expire);
+            let resumed = loop {
+                tokio::select! {
+                    _ = &mut stopped => break true,
+                    _ = &mut expire => break false,
+                    _ = state.shutting_down() => break false,
+                    msg = outbox.recv() => match msg {
+                        Some(Outgoing::Close { .. }) | None => break false,
+                        Some(Outgoing::Message(msg)) => {
+                            missed.push_back(msg);
+                            if missed.len() > CONFIG.resume_buffer {
+                                missed.pop_front();
+                                complete = false;
+                            }
+                        }
+                    }
+                }
+            };
+            // A connection may have claimed the session right as it expired, it is still waiting for it then
+            let resumed =
+                resumed || (state.resumable.parked.lock().remove(&token_clone).is_none() && stopped.await.is_ok());
+            if resumed {
+                return Some(Resumed {
+                    session,
+                    outbox,
+                    complete,
+                    missed,
+                });
+            }
+            state.player_disconnected(&session);
+            None
+        });
+        parked.insert(token, Parked { stop, task });
+    }
+
+    /// Takes over a parked session, None if the token is unknown or expired.
+    pub async fn resume(&self, token: &str) -> Option<Resumed> {
+        let parked = self.parked.lock().remove(token)?;
+        let _ = parked.stop.send(());
+        parked.task.await.ok().flatten()
+    }
+
+    /// Amount of sessions waiting to be resumed.
+    pub fn parked_count(&self) -> usize {
+        self.parked.lock().len()
+    }
+}
+
+#[test]
+fn registry_sends_to_every_connection() {
+    let registry = SessionRegistry::new(10);
+    let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+    let first_session = Session::new(registry.new_id(), a, Codec::Json, Vec::new());
+    let first = registry.register(&first_session);
+    let second = registry.register(&Session::new(registry.new_id(), a, Codec::Json, Vec::new()));
+    let other = registry.register(&Session::new(registry.new_id(), b, Codec::Json, Vec::new()));
+    assert_eq!(registry.count(), 3);
+
+    assert!(registry.send(a, InternalMessages::CosmeticsAck));
+    assert_eq!((first.queued(), second.queued(), other.queued()), (1, 1, 0));
+    // Nobody receives a pong meant for someone else or anything sent to a player that isn't connected
+    assert!(!registry.send(b, InternalMessages::Pong { nonce: None, uuid: a }));
+    assert!(!registry.send(Uuid::new_v4(), InternalMessages::CosmeticsAck));
+    // Replies only go to the connection that asked
+    registry.reply(&first, InternalMessages::Pong { nonce: None, uuid: a });
+    assert_eq!((first.queued(), second.queued(), other.queued()), (2, 1, 0));
+
+    registry.unregister(&first_session);
+    registry.send_many(&[a, b], InternalMessages::CosmeticsAck);
+    registry.broadcast(InternalMessages::CosmeticsAck);
+    assert_eq!((first.queued(), second.queued(), other.queued()), (2, 3, 2));
+    assert_eq!((registry.count(), registry.queued()), (2, 5));
+}
+
+#[test]
+fn full_outboxes_drop_coalesce_or_give_up() {
+    let registry = SessionRegistry::new(1);
+    let uuid = Uuid::new_v4();
+    let outbox = registry.register(&Session::new(registry.new_id(), uuid, Codec::Json, Vec::new()));
+    let presence = |online| InternalMessages::PresenceUpdate { uuid, online };
+
+    registry.send(uuid, InternalMessages::CosmeticsAck);
+    registry.send(uuid, InternalMessages::CosmeticsAck);
+    registry.send(uuid, presence(true));
+    registry.send(uuid, presence(false));
+    assert_eq!(registry.dropped.load(Ordering::Relaxed), 1);
+    assert_eq!(registry.coalesced.load(Ordering::Relaxed), 2);
+    assert_eq!(outbox.queued(), 2);
+
+    // Presence of other users is only coalesced up to the capacity
+    let other = InternalMessages::PresenceUpdate {
+        uuid: Uuid::new_v4(),
+        online: true,
+    };
+    assert_eq!(outbox.push(other), Pushed::TooSlow);
+    assert_eq!(
+        outbox.push(InternalMessages::Pong { nonce: None, uuid }),
+        Pushed::Dropped
+    );
+}
+
+#[test]
+fn outboxes_only_queue_what_the_session_receives() {
+    let registry = SessionRegistry::new(1);
+    let uuid = Uuid::new_v4();
+    let outbox = registry.register(&Session::new(registry.new_id(), uuid, Codec::Json, Vec::new()));
+    let pong = |uuid| InternalMessages::Pong { nonce: None, uuid };
+
+    registry.broadcast(pong(Uuid::new_v4()));
+    assert_eq!(outbox.push(pong(Uuid::new_v4())), Pushed::Ignored);
+    assert_eq!(outbox.push(pong(uuid)), Pushed::Queued);
+    assert_eq!(outbox.queued(), 1);
+    assert_eq!(registry.dropped.load(Ordering::Relaxed), 0);
+
+    // Presence replacing a pending update while there is room isn't counted as coalesced
+    let presence = |online| InternalMessages::PresenceUpdate { uuid, online };
+    assert_eq!(outbox.push(presence(true)), Pushed::Coalesced);
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    assert!(matches!(
+        runtime.block_on(outbox.recv()),
+        Some(Outgoing::Message(Messages::Pong(None)))
+    ));
+    assert_eq!(outbox.push(presence(false)), Pushed::Queued);
+    assert_eq!(outbox.queued(), 1);
+}
+
+#[test]
+fn presence_goes_to_subscribers_and_friends() {
+    let registry = SessionRegistry::new(10);
+    let (player, friend, stranger) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let subscriber = Session::new(registry.new_id(), stranger, Codec::Json, Vec::new());
+    let subscribed = registry.register(&subscriber);
+    let unsubscribed = registry.register(&Session::new(registry.new_id(), stranger, Codec::Json, Vec::new()));
+    let with_friends = Session::new(registry.new_id(), friend, Codec::Json, vec![Capability::Friends]);
+    let with_friends = registry.register(&with_friends);
+    let without_friends = registry.register(&Session::new(registry.new_id(), friend, Codec::Json, Vec::new()));
+
+    subscriber.subscriptions.lock().insert(player);
+    registry.subscribe(&subscriber, &[player]);
+    registry.send_presence(player, true, &HashSet::from([friend]));
+    let queued = || [&subscribed, &unsubscribed, &with_friends, &without_friends].map(|o| o.queued());
+    assert_eq!(queued(), [1, 0, 1, 0]);
+
+    registry.unregister(&subscriber);
+    registry.send_presence(player, false, &HashSet::new());
+    assert_eq!(queued(), [1, 0, 1, 0]);
+}
+
+#[test]
+fn messages_need_their_capability() {
+    let uuid = Uuid::new_v4();
+    let dm = || InternalMessages::Dm {
+        message: "hi".to_owned(),
+        sender: Uuid::new_v4(),
+        to: uuid,
+        date: 0,
+    };
+    let invite = || InternalMessages::PartyInvited {
+        to: uuid,
+        party: Uuid::new_v4(),
+        from: Uuid::new_v4(),
+    };
+    let legacy = Session::new(0, uuid, Codec::Json, Vec::new());
+    assert!(legacy.route(dm()).is_none());
+    assert!(legacy.route(invite()).is_none());
+
+    let session = Session::new(1, uuid, Codec::Json, vec![Capability::Dm]);
+    assert!(matches!(session.route(dm()), Some(Messages::DmReceived { .. })));
+    assert!(session.route(invite()).is_none());
+}
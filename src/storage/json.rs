@@ -0,0 +1,374 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::{self, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    path::Path,
+    sync::{mpsc, Arc},
+    thread,
+};
+
+use anyhow::anyhow;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    app_state::{Cosmetic, User},
+    error::Result,
+    storage::Storage,
+    utils::{
+        atomic_write,
+        retrieve_cosmetics::{parse_cosmetic_file, CosmeticFile},
+    },
+};
+
+/// The original cosmetics.json file.
+///
+/// The file itself is only rewritten when the whole state is saved, every change in between is appended to a
+/// journal next to it which is replayed on top of the file when loading. Appends are queued to a writer thread so
+/// callers holding the users or cosmetics lock don't wait on the disk.
+///
+/// Saving moves the journal aside before taking the snapshot and deletes it once the file is written, so a change is
+/// always in the saved file or in a journal. Callers have to change the in memory state and write it through while
+/// holding the lock of what changed, otherwise a snapshot can miss a change that was moved aside.
+pub struct JsonStorage {
+    path: String,
+    journal_path: String,
+    /// The journal of a save that is in progress or failed, replayed before the current one
+    old_journal_path: String,
+    /// Held while the journal is appended to, moved aside or replayed
+    journal: Arc<Mutex<()>>,
+    writes: mpsc::Sender<Queued>,
+    saving: Mutex<()>,
+    /// Hash of the contents of the last save
+    written: Mutex<Option<u64>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum JournalEntry {
+    PutUser { uuid: Uuid, user: User },
+    RemoveUser { uuid: Uuid },
+    PutCosmetic { cosmetic: Cosmetic },
+    RemoveCosmetic { id: u8 },
+}
+
+impl JournalEntry {
+    fn apply(self, file: &mut CosmeticFile) {
+        match self {
+            JournalEntry::PutUser { uuid, user } => {
+                file.users.insert(uuid, user);
+            }
+            JournalEntry::RemoveUser { uuid } => {
+                file.users.remove(&uuid);
+            }
+            JournalEntry::PutCosmetic { cosmetic } => {
+                file.cosmetics.retain(|c| c.id != cosmetic.id);
+                file.cosmetics.push(cosmetic);
+            }
+            JournalEntry::RemoveCosmetic { id } => file.cosmetics.retain(|c| c.id != id),
+        }
+    }
+}
+
+enum Queued {
+    Line(String),
+    /// Told once every line queued before it is written
+    Flush(mpsc::Sender<()>),
+}
+
+impl JsonStorage {
+    pub fn new(path: &str) -> Self {
+        let journal_path = format!("{path}.journal");
+        let journal = Arc::new(Mutex::new(()));
+        let (writes, rx) = mpsc::channel();
+        let writer = (journal_path.clone(), journal.clone());
+        thread::Builder::new()
+            .name("journal-writer".to_string())
+            .spawn(move || {
+                let (path, journal) = writer;
+                while let Ok(first) = rx.recv() {
+                    // Everything that queued up while the last write was synced goes out with one sync
+                    let mut lines = String::new();
+                    let mut flushed = Vec::new();
+                    for queued in std::iter::once(first).chain(rx.try_iter()) {
+                        match queued {
+                            Queued::Line(line) => lines.push_str(&line),
+                            Queued::Flush(done) => flushed.push(done),
+                        }
+                    }
+                    if !lines.is_empty() {
+                        let _guard = journal.lock();
+                        if let Err(e) = append(&path, &lines) {
+                            tracing::error!("Failed to write to the journal: {:?}", e);
+                        }
+                    }
+                    for done in flushed {
+                        let _ = done.send(());
+                    }
+                }
+            })
+            .expect("Failed to start the journal writer");
+        Self {
+            path: path.to_owned(),
+            journal_path,
+            old_journal_path: format!("{path}.journal.old"),
+            journal,
+            writes,
+            saving: Mutex::new(()),
+            written: Mutex::new(None),
+        }
+    }
+
+    /// Queues an entry for the writer thread, errors writing it are logged there.
+    fn queue(&self, entry: JournalEntry) -> Result<()> {
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        self.writes
+            .send(Queued::Line(line))
+            .map_err(|_| anyhow!("The journal writer stopped"))?;
+        Ok(())
+    }
+
+    /// Waits until every entry queued so far is written.
+    fn flush(&self) {
+        let (done, flushed) = mpsc::channel();
+        if self.writes.send(Queued::Flush(done)).is_ok() {
+            let _ = flushed.recv();
+        }
+    }
+
+    /// Moves the journal aside so it can be deleted once the snapshot taken after this is saved, appending to what
+    /// is left of a save that failed.
+    fn rotate(&self) -> io::Result<()> {
+        self.flush();
+        let _guard = self.journal.lock();
+        let journal = match fs::read(&self.journal_path) {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if Path::new(&self.old_journal_path).exists() {
+            let mut old = OpenOptions::new().append(true).open(&self.old_journal_path)?;
+            old.write_all(&journal)?;
+            old.sync_data()?;
+            fs::remove_file(&self.journal_path)
+        } else {
+            fs::rename(&self.journal_path, &self.old_journal_path)
+        }
+    }
+
+    /// Applies the entries of a journal to `file`, cutting off a last line that was only partly written.
+    fn replay(path: &str, file: &mut CosmeticFile) -> io::Result<usize> {
+        let mut journal = match fs::read_to_string(path) {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        if !journal.is_empty() && !journal.ends_with('\n') {
+            tracing::warn!("Cutting off an interrupted write at the end of {}", path);
+            journal.truncate(journal.rfind('\n').map_or(0, |i| i + 1));
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(journal.len() as u64)?;
+        }
+
+        let mut replayed = 0;
+        for line in journal.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str::<JournalEntry>(line) {
+                Ok(entry) => {
+                    entry.apply(file);
+                    replayed += 1;
+                }
+                Err(e) => tracing::warn!("Skipping unreadable journal entry: {}", e),
+            }
+        }
+        if replayed > 0 {
+            tracing::info!("Replayed {} journal entries from {}", replayed, path);
+        }
+        Ok(replayed)
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load(&self) -> Result<CosmeticFile> {
+        let mut file = match fs::read_to_string(&self.path) {
+            Ok(file) => parse_cosmetic_file(&file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                tracing::warn!("{} does not exist, starting without cosmetics", self.path);
+                CosmeticFile::default()
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        self.apply_pending(&mut file)?;
+        Ok(file)
+    }
+
+    fn save(&self, snapshot: &dyn Fn() -> CosmeticFile) -> Result<()> {
+        let _saving = self.saving.lock();
+        // Everything journaled before this point is part of the snapshot, anything after it goes to a new journal
+        self.rotate()?;
+        let file = snapshot();
+        let contents = serde_json::to_string_pretty(&file)?;
+        *self.written.lock() = Some(hash(&contents));
+        atomic_write(&self.path, contents)?;
+        match fs::remove_file(&self.old_journal_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn apply_pending(&self, file: &mut CosmeticFile) -> Result<()> {
+        // Replaying can cut off what looks like a torn write, which would be an append in progress without the lock
+        self.flush();
+        let _guard = self.journal.lock();
+        Self::replay(&self.old_journal_path, file)?;
+        Self::replay(&self.journal_path, file)?;
+        Ok(())
+    }
+
+    fn put_user(&self, uuid: Uuid, user: &User) -> Result<()> {
+        self.queue(JournalEntry::PutUser {
+            uuid,
+            user: user.clone(),
+        })
+    }
+
+    fn remove_user(&self, uuid: Uuid) -> Result<()> {
+        self.queue(JournalEntry::RemoveUser { uuid })
+    }
+
+    fn put_cosmetic(&self, cosmetic: &Cosmetic) -> Result<()> {
+        self.queue(JournalEntry::PutCosmetic {
+            cosmetic: cosmetic.clone(),
+        })
+    }
+
+    fn remove_cosmetic(&self, id: u8) -> Result<()> {
+        self.queue(JournalEntry::RemoveCosmetic { id })
+    }
+
+    fn wrote(&self, contents: &str) -> bool {
+        *self.written.lock() == Some(hash(contents))
+    }
+}
+
+fn append(path: &str, lines: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    if let Err(e) = file.write_all(lines.as_bytes()).and_then(|_| file.sync_data()) {
+        // Don't leave half a line behind for the next entry to be glued to
+        let _ = file.set_len(len);
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn hash(contents: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A storage in a new temp dir and a way to make users that can be told apart.
+#[cfg(test)]
+fn test_storage() -> (std::path::PathBuf, JsonStorage, fn(u8) -> User) {
+    let dir = std::env::temp_dir().join(format!("dws-json-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    let storage = JsonStorage::new(dir.join("cosmetics.json").to_str().unwrap());
+    let user_with_prefix = |prefix| User {
+        enabled_prefix: Some(prefix),
+        ..Default::default()
+    };
+    (dir, storage, user_with_prefix)
+}
+
+#[test]
+fn journal_is_replayed_on_load() {
+    let (dir, storage, user_with_prefix) = test_storage();
+    let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+    storage.put_user(a, &user_with_prefix(1)).unwrap();
+    storage.save(&|| storage.load().unwrap()).unwrap();
+    storage.put_user(b, &user_with_prefix(2)).unwrap();
+    storage.put_user(a, &user_with_prefix(3)).unwrap();
+    storage.remove_user(b).unwrap();
+
+    let file = storage.load().unwrap();
+    assert_eq!(file.users.len(), 1);
+    assert_eq!(file.users[&a].enabled_prefix, Some(3));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn torn_journal_tail_is_cut_off() {
+    let (dir, storage, user_with_prefix) = test_storage();
+    let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+    storage.put_user(a, &user_with_prefix(1)).unwrap();
+    storage.flush();
+    let mut journal = OpenOptions::new().append(true).open(&storage.journal_path).unwrap();
+    journal.write_all(br#"{"op":"put_user","uuid":"#).unwrap();
+    drop(journal);
+
+    assert_eq!(storage.load().unwrap().users.len(), 1);
+    // The next entry starts on a line of its own instead of being glued to the torn one
+    storage.put_user(b, &user_with_prefix(2)).unwrap();
+    let file = storage.load().unwrap();
+    assert_eq!(file.users[&a].enabled_prefix, Some(1));
+    assert_eq!(file.users[&b].enabled_prefix, Some(2));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn saving_keeps_concurrent_changes() {
+    let (dir, storage, user_with_prefix) = test_storage();
+    let users = Mutex::new(std::collections::HashMap::new());
+    let uuids: Vec<Uuid> = (0..50).map(|_| Uuid::new_v4()).collect();
+    let snapshot = || CosmeticFile {
+        users: users.lock().clone(),
+        ..Default::default()
+    };
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for round in 0..20u8 {
+                for uuid in &uuids {
+                    let mut users = users.lock();
+                    let user = user_with_prefix(round);
+                    users.insert(*uuid, user.clone());
+                    storage.put_user(*uuid, &user).unwrap();
+                }
+            }
+        });
+        for _ in 0..20 {
+            storage.save(&snapshot).unwrap();
+        }
+    });
+
+    let file = storage.load().unwrap();
+    assert_eq!(file.users.len(), uuids.len());
+    assert!(file.users.values().all(|u| u.enabled_prefix == Some(19)));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn edited_file_keeps_journaled_changes() {
+    let (dir, storage, user_with_prefix) = test_storage();
+    let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+    storage.put_user(a, &user_with_prefix(1)).unwrap();
+    storage.save(&|| storage.load().unwrap()).unwrap();
+    storage.put_user(a, &user_with_prefix(2)).unwrap();
+
+    // Someone adds a user by hand, from the file as it was before the journaled change
+    let mut edited = parse_cosmetic_file(&fs::read_to_string(&storage.path).unwrap()).unwrap();
+    edited.users.insert(b, user_with_prefix(3));
+    fs::write(&storage.path, serde_json::to_string(&edited).unwrap()).unwrap();
+
+    let mut file = parse_cosmetic_file(&fs::read_to_string(&storage.path).unwrap()).unwrap();
+    storage.apply_pending(&mut file).unwrap();
+    assert_eq!(file.users[&a].enabled_prefix, Some(2));
+    assert_eq!(file.users[&b].enabled_prefix, Some(3));
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,71 @@
+use clap::ValueEnum;
+use uuid::Uuid;
+
+use crate::{
+    app_state::{Cosmetic, User},
+    error::Result,
+    utils::retrieve_cosmetics::CosmeticFile,
+};
+
+pub use json::JsonStorage;
+pub use sqlite::SqliteStorage;
+
+mod json;
+mod sqlite;
+
+/// Where users and cosmetics are persisted.
+///
+/// The in memory maps on [`crate::app_state::AppState`] stay the source of truth, every mutation is written through
+/// to the backend so it can decide how durable it wants to be.
+pub trait Storage: Send + Sync {
+    /// Loads everything that was persisted.
+    fn load(&self) -> Result<CosmeticFile>;
+    /// Replaces everything that was persisted with the state returned by `snapshot`.
+    ///
+    /// The snapshot is taken by the backend so it can line it up with changes that are written through concurrently.
+    fn save(&self, snapshot: &dyn Fn() -> CosmeticFile) -> Result<()>;
+    /// Makes sure every change is on disk, called every few minutes and on shutdown.
+    fn checkpoint(&self, snapshot: &dyn Fn() -> CosmeticFile) -> Result<()> {
+        self.save(snapshot)
+    }
+    /// Applies changes that were written through but aren't in the cosmetics file yet, so a file that was edited on
+    /// disk doesn't lose them.
+    fn apply_pending(&self, _file: &mut CosmeticFile) -> Result<()> {
+        Ok(())
+    }
+    fn put_user(&self, uuid: Uuid, user: &User) -> Result<()>;
+    fn remove_user(&self, uuid: Uuid) -> Result<()>;
+    fn put_cosmetic(&self, cosmetic: &Cosmetic) -> Result<()>;
+    fn remove_cosmetic(&self, id: u8) -> Result<()>;
+    /// Whether `contents` is exactly what was last written to the cosmetics file, so watchers can ignore our own
+    /// saves.
+    fn wrote(&self, _contents: &str) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StorageKind {
+    /// Dumps everything to the cosmetics file every few minutes
+    Json,
+    /// Writes every change to a sqlite database in the background as it happens
+    Sqlite,
+}
+
+pub fn open(kind: StorageKind, cosmetics_file: &str, database_file: &str) -> Result<Box<dyn Storage>> {
+    Ok(match kind {
+        StorageKind::Json => Box::new(JsonStorage::new(cosmetics_file)),
+        StorageKind::Sqlite => {
+            let storage = SqliteStorage::open(database_file)?;
+            // Carry over an existing cosmetics file the first time the database is used
+            if storage.is_empty()? {
+                let file = JsonStorage::new(cosmetics_file).load()?;
+                if !file.users.is_empty() || !file.cosmetics.is_empty() {
+                    tracing::info!("Importing {} into {}", cosmetics_file, database_file);
+                    storage.save(&|| file.clone())?;
+                }
+            }
+            Box::new(storage)
+        }
+    })
+}
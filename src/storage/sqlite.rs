@@ -0,0 +1,223 @@
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+};
+
+use anyhow::anyhow;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+use crate::{
+    app_state::{Cosmetic, User},
+    error::Result,
+    storage::Storage,
+    utils::retrieve_cosmetics::{migrate, CosmeticFile, COSMETICS_VERSION},
+};
+
+/// Stores users and cosmetics as json rows in a sqlite database.
+///
+/// Changes are committed in order by a writer thread, so callers that hold the users or cosmetics lock don't wait on
+/// the disk. The cosmetics file version is kept in `PRAGMA user_version` so the rows go through the same migrations.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+    writes: mpsc::Sender<(Write, Option<Done>)>,
+}
+
+/// Told the result once a write is committed.
+type Done = mpsc::Sender<Result<()>>;
+
+enum Write {
+    PutUser(Uuid, String),
+    RemoveUser(Uuid),
+    PutCosmetic(u8, String),
+    RemoveCosmetic(u8),
+    Replace(CosmeticFile),
+    Checkpoint,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = NORMAL;
+            CREATE TABLE IF NOT EXISTS users (uuid TEXT PRIMARY KEY NOT NULL, data TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS cosmetics (id INTEGER PRIMARY KEY NOT NULL, data TEXT NOT NULL);",
+        )?;
+        let conn = Arc::new(Mutex::new(conn));
+
+        let (writes, rx) = mpsc::channel::<(Write, Option<Done>)>();
+        let writer = conn.clone();
+        thread::Builder::new()
+            .name("sqlite-writer".to_string())
+            .spawn(move || {
+                for (write, done) in rx {
+                    let result = apply(&mut writer.lock(), write);
+                    match done {
+                        Some(done) => {
+                            let _ = done.send(result);
+                        }
+                        None => {
+                            if let Err(e) = result {
+                                tracing::error!("Failed to write to the database: {:?}", e);
+                            }
+                        }
+                    }
+                }
+            })?;
+        Ok(Self { conn, writes })
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        let conn = self.conn.lock();
+        let count: i64 = conn.query_row(
+            "SELECT (SELECT COUNT(*) FROM users) + (SELECT COUNT(*) FROM cosmetics)",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(count == 0)
+    }
+
+    /// Queues a write without waiting for it, errors are logged by the writer.
+    fn queue(&self, write: Write) -> Result<()> {
+        self.writes
+            .send((write, None))
+            .map_err(|_| anyhow!("The database writer stopped"))?;
+        Ok(())
+    }
+
+    /// Queues a write and waits until it and everything queued before it is committed.
+    fn wait(&self, write: Write) -> Result<()> {
+        let (done, result) = mpsc::channel();
+        self.writes
+            .send((write, Some(done)))
+            .map_err(|_| anyhow!("The database writer stopped"))?;
+        result.recv().map_err(|_| anyhow!("The database writer stopped"))?
+    }
+}
+
+fn apply(conn: &mut Connection, write: Write) -> Result<()> {
+    match write {
+        Write::PutUser(uuid, data) => {
+            conn.execute(
+                "INSERT OR REPLACE INTO users (uuid, data) VALUES (?1, ?2)",
+                params![uuid.to_string(), data],
+            )?;
+        }
+        Write::RemoveUser(uuid) => {
+            conn.execute("DELETE FROM users WHERE uuid = ?1", params![uuid.to_string()])?;
+        }
+        Write::PutCosmetic(id, data) => {
+            conn.execute(
+                "INSERT OR REPLACE INTO cosmetics (id, data) VALUES (?1, ?2)",
+                params![id, data],
+            )?;
+        }
+        Write::RemoveCosmetic(id) => {
+            conn.execute("DELETE FROM cosmetics WHERE id = ?1", params![id])?;
+        }
+        Write::Replace(file) => write_all(conn, &file)?,
+        Write::Checkpoint => conn.execute_batch("PRAGMA wal_checkpoint(PASSIVE)")?,
+    }
+    Ok(())
+}
+
+fn write_all(conn: &mut Connection, file: &CosmeticFile) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM users", [])?;
+    tx.execute("DELETE FROM cosmetics", [])?;
+    for (uuid, user) in &file.users {
+        tx.execute(
+            "INSERT INTO users (uuid, data) VALUES (?1, ?2)",
+            params![uuid.to_string(), serde_json::to_string(user)?],
+        )?;
+    }
+    for cosmetic in &file.cosmetics {
+        tx.execute(
+            "INSERT OR REPLACE INTO cosmetics (id, data) VALUES (?1, ?2)",
+            params![cosmetic.id, serde_json::to_string(cosmetic)?],
+        )?;
+    }
+    tx.execute_batch(&format!("PRAGMA user_version = {COSMETICS_VERSION}"))?;
+    tx.commit()?;
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> Result<CosmeticFile> {
+        let mut value = {
+            let conn = self.conn.lock();
+            let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+            let mut cosmetics = Vec::new();
+            let mut stmt = conn.prepare("SELECT data FROM cosmetics ORDER BY id")?;
+            for data in stmt.query_map([], |row| row.get::<_, String>(0))? {
+                cosmetics.push(serde_json::from_str::<Value>(&data?)?);
+            }
+
+            let mut users = Map::new();
+            let mut stmt = conn.prepare("SELECT uuid, data FROM users")?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+            for row in rows {
+                let (uuid, data) = row?;
+                users.insert(uuid, serde_json::from_str(&data)?);
+            }
+            json!({ "version": version, "cosmetics": cosmetics, "users": users })
+        };
+
+        let from = migrate(&mut value)?;
+        let file: CosmeticFile = serde_json::from_value(value)?;
+        if from != COSMETICS_VERSION {
+            tracing::info!("Migrated database from version {} to {}", from, COSMETICS_VERSION);
+            write_all(&mut self.conn.lock(), &file)?;
+        }
+        Ok(file)
+    }
+
+    fn save(&self, snapshot: &dyn Fn() -> CosmeticFile) -> Result<()> {
+        self.wait(Write::Replace(snapshot()))
+    }
+
+    /// Every change is already written through, this only waits for the queued ones.
+    fn checkpoint(&self, _snapshot: &dyn Fn() -> CosmeticFile) -> Result<()> {
+        self.wait(Write::Checkpoint)
+    }
+
+    fn put_user(&self, uuid: Uuid, user: &User) -> Result<()> {
+        self.queue(Write::PutUser(uuid, serde_json::to_string(user)?))
+    }
+
+    fn remove_user(&self, uuid: Uuid) -> Result<()> {
+        self.queue(Write::RemoveUser(uuid))
+    }
+
+    fn put_cosmetic(&self, cosmetic: &Cosmetic) -> Result<()> {
+        self.queue(Write::PutCosmetic(cosmetic.id, serde_json::to_string(cosmetic)?))
+    }
+
+    fn remove_cosmetic(&self, id: u8) -> Result<()> {
+        self.queue(Write::RemoveCosmetic(id))
+    }
+}
+
+#[test]
+fn changes_are_written_in_order() {
+    let path = std::env::temp_dir().join(format!("dws-sqlite-{}.db", Uuid::new_v4()));
+    let storage = SqliteStorage::open(path.to_str().unwrap()).unwrap();
+    let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+    storage.put_user(a, &User::default()).unwrap();
+    storage.put_user(b, &User::default()).unwrap();
+    storage.remove_user(a).unwrap();
+    storage.checkpoint(&CosmeticFile::default).unwrap();
+    assert_eq!(storage.load().unwrap().users.keys().collect::<Vec<_>>(), [&b]);
+
+    // A full save replaces everything that was written through
+    storage.save(&CosmeticFile::default).unwrap();
+    assert!(storage.is_empty().unwrap());
+    drop(storage);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+}
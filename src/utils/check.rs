@@ -0,0 +1,174 @@
+use std::{collections::HashMap, fmt};
+
+use serde_json::Value;
+
+use crate::{
+    app_state::{Cosmetic, User},
+    bitflags::CosmeticFlags,
+    utils::retrieve_cosmetics::migrate,
+};
+
+/// Something wrong with a cosmetics file, `path` points at the offending value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Reports everything that would stop a cosmetics file from loading or make it behave unexpectedly.
+pub fn check_cosmetic_file(contents: &str) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |path: String, message: String| problems.push(Problem { path, message });
+
+    let mut file: Value = match serde_json::from_str(contents) {
+        Ok(file) => file,
+        Err(e) => {
+            problem("$".to_owned(), format!("Invalid json: {e}"));
+            return problems;
+        }
+    };
+    if let Err(e) = migrate(&mut file) {
+        problem("$.version".to_owned(), e.0.to_string());
+        return problems;
+    }
+
+    let mut cosmetics = HashMap::new();
+    for (i, value) in file["cosmetics"].as_array().into_iter().flatten().enumerate() {
+        let path = format!("$.cosmetics[{i}]");
+        let id = match value["id"].as_u64().and_then(|id| u8::try_from(id).ok()) {
+            Some(id) => id,
+            None => {
+                problem(format!("{path}.id"), format!("Invalid cosmetic id {}", value["id"]));
+                continue;
+            }
+        };
+        if !check_flags(
+            &value["required_flags"],
+            &format!("{path}.required_flags"),
+            &mut problem,
+        ) {
+            continue;
+        }
+        let cosmetic = match serde_json::from_value::<Cosmetic>(value.clone()) {
+            Ok(cosmetic) => cosmetic,
+            Err(e) => {
+                problem(path, e.to_string());
+                continue;
+            }
+        };
+        if let Some((first, _)) = cosmetics.get(&id) {
+            problem(
+                format!("{path}.id"),
+                format!("Duplicate cosmetic id {id}, also used by {first}"),
+            );
+            continue;
+        }
+        cosmetics.insert(id, (path, cosmetic));
+    }
+
+    for (uuid, value) in file["users"].as_object().into_iter().flatten() {
+        let path = format!("$.users[\"{uuid}\"]");
+        if let Err(e) = uuid::Uuid::parse_str(uuid) {
+            problem(path.clone(), format!("Invalid uuid: {e}"));
+        }
+        if !check_flags(&value["flags"], &format!("{path}.flags"), &mut problem) {
+            continue;
+        }
+        let user = match serde_json::from_value::<User>(value.clone()) {
+            Ok(user) => user,
+            Err(e) => {
+                problem(path, e.to_string());
+                continue;
+            }
+        };
+        if let Some(id) = user.enabled_prefix {
+            match cosmetics.get(&id) {
+                None => problem(
+                    format!("{path}.enabled_prefix"),
+                    format!("Cosmetic {id} does not exist"),
+                ),
+                Some((_, cosmetic)) if !user.flags.contains(cosmetic.required_flags) => problem(
+                    format!("{path}.enabled_prefix"),
+                    format!(
+                        "Flags {:#X} don't include the required flags {:#X} of cosmetic {id}",
+                        user.flags.bits(),
+                        cosmetic.required_flags.bits()
+                    ),
+                ),
+                Some(_) => {}
+            }
+        }
+    }
+    problems
+}
+
+/// Reports flags that aren't a known combination of [`CosmeticFlags`], missing flags are fine.
+fn check_flags(value: &Value, path: &str, problem: &mut impl FnMut(String, String)) -> bool {
+    if value.is_null() {
+        return true;
+    }
+    match value.as_u64().and_then(|bits| u32::try_from(bits).ok()) {
+        Some(bits) if CosmeticFlags::from_bits(bits).is_some() => true,
+        Some(bits) => {
+            let unknown = bits & !CosmeticFlags::all().bits();
+            problem(path.to_owned(), format!("Invalid flag bits {unknown:#X}"));
+            false
+        }
+        None => {
+            problem(path.to_owned(), format!("Invalid flags {value}"));
+            false
+        }
+    }
+}
+
+/// Runs [`check_cosmetic_file`] on a file and prints the problems, returns whether the file is fine.
+pub fn check_file(path: &str) -> bool {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("{path}: {e}");
+            return false;
+        }
+    };
+    let problems = check_cosmetic_file(&contents);
+    for problem in &problems {
+        println!("{path}: {problem}");
+    }
+    if problems.is_empty() {
+        println!("{path}: ok");
+    }
+    problems.is_empty()
+}
+
+#[test]
+fn reports_problems_with_paths() {
+    let problems = check_cosmetic_file(
+        r#"{
+            "cosmetics": [
+                { "id": 1, "name": "a", "description": "", "data": "", "required_flags": 32 },
+                { "id": 1, "name": "b", "description": "", "data": "", "required_flags": 1024 },
+                { "id": 1, "name": "c", "description": "", "data": "", "required_flags": 2 }
+            ],
+            "users": {
+                "41a9b6aa-168a-4be8-8df8-cac17daf7384": { "flags": 8, "enabled_prefix": 1 },
+                "4e29caf5-9317-454b-8863-eca22877e0ec": { "flags": 32, "enabled_prefix": 3 }
+            }
+        }"#,
+    );
+    let paths = problems.iter().map(|p| p.path.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        [
+            "$.cosmetics[1].required_flags",
+            "$.cosmetics[2].id",
+            "$.users[\"41a9b6aa-168a-4be8-8df8-cac17daf7384\"].enabled_prefix",
+            "$.users[\"4e29caf5-9317-454b-8863-eca22877e0ec\"].enabled_prefix",
+        ]
+    );
+}
@@ -0,0 +1,81 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use notify::{event::EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{
+    app_state::AppState,
+    config::CONFIG,
+    error::Result,
+    messages::InternalMessages,
+    utils::{check::check_cosmetic_file, retrieve_cosmetics::parse_cosmetic_file},
+};
+
+/// Watches the cosmetics file and reloads it whenever it is changed by something other than us.
+pub async fn watch_cosmetics(state: Arc<AppState>) -> Result<()> {
+    let path = PathBuf::from(&CONFIG.cosmetics_file);
+    let file_name = path.file_name().map(|n| n.to_owned());
+    // Editors and deploys usually replace the file instead of writing to it, so watch the directory
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => PathBuf::from("."),
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+        Ok(event) => {
+            let ours = event.paths.iter().any(|p| p.file_name() == file_name.as_deref());
+            if ours && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                let _ = tx.send(());
+            }
+        }
+        Err(e) => tracing::error!("Error watching cosmetics file: {:?}", e),
+    })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    tracing::debug!("Watching {} for changes", path.display());
+
+    while rx.recv().await.is_some() {
+        // Let the writer finish, one save tends to fire a handful of events
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        while rx.try_recv().is_ok() {}
+
+        if let Err(e) = reload(&state, &path).await {
+            tracing::error!(
+                "Not reloading {}, keeping the current cosmetics: {:?}",
+                path.display(),
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn reload(state: &AppState, path: &Path) -> Result<()> {
+    let contents = tokio::fs::read_to_string(path).await?;
+    if state.storage.wrote(&contents) {
+        return Ok(());
+    }
+    let problems = check_cosmetic_file(&contents);
+    if !problems.is_empty() {
+        let problems = problems.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        return Err(anyhow!("{}", problems.join(", ")).into());
+    }
+    let file = parse_cosmetic_file(&contents)?;
+
+    tracing::info!(
+        "Reloading {} with {} cosmetics and {} users",
+        path.display(),
+        file.cosmetics.len(),
+        file.users.len()
+    );
+    state.reload_cosmetics(file)?;
+    // Rewrites the file in the current format with the journaled changes and clears the journal
+    state.save()?;
+    state.broadcast(InternalMessages::CosmeticsAck);
+    Ok(())
+}
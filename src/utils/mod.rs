@@ -1,10 +1,15 @@
+mod atomic_write;
+pub mod check;
+pub mod hot_reload;
 mod influx;
 pub mod retrieve_cosmetics;
 pub mod sanitize;
-mod set_ctrlc;
+mod shutdown;
+pub mod snapshots;
 mod uuid_utils;
 mod validate_session;
+pub use atomic_write::atomic_write;
 pub use influx::Influx;
-pub use set_ctrlc::set_ctrlc;
+pub use shutdown::shutdown_signal;
 pub use uuid_utils::{username_to_uuid_and_discord, uuid_to_username, UuidAndUsername};
 pub use validate_session::validate_session;
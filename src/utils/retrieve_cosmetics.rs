@@ -1,25 +1,119 @@
 use std::collections::HashMap;
 
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use uuid::Uuid;
 
 use crate::{
     app_state::{Cosmetic, User},
-    config::CONFIG,
+    error::Result,
+    storage::Storage,
+    utils::atomic_write,
 };
 
-#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+/// Version of the cosmetics file written by this build, bump it together with a new entry in [`MIGRATIONS`].
+pub const COSMETICS_VERSION: u32 = 1;
+
+/// Upgrades a cosmetics file from the version at its index to the next one.
+const MIGRATIONS: [fn(&mut Value) -> Result<()>; COSMETICS_VERSION as usize] = [v0_to_v1];
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CosmeticFile {
+    #[serde(default)]
+    pub version: u32,
     #[serde(default)]
     pub cosmetics: Vec<Cosmetic>,
     #[serde(default)]
     pub users: HashMap<Uuid, User>,
 }
 
-pub async fn retrieve_cosmetics() -> CosmeticFile {
-    if let Ok(file) = &tokio::fs::read_to_string(&CONFIG.cosmetics_file).await {
-        serde_json::from_str(file).expect("Failed to parse cosmetics.json")
-    } else {
-        CosmeticFile::default()
+impl Default for CosmeticFile {
+    fn default() -> Self {
+        Self {
+            version: COSMETICS_VERSION,
+            cosmetics: Vec::new(),
+            users: HashMap::new(),
+        }
     }
 }
+
+pub fn retrieve_cosmetics(storage: &dyn Storage) -> CosmeticFile {
+    storage.load().expect("Failed to load cosmetics")
+}
+
+/// Files from before the version key existed, the layout itself is unchanged.
+fn v0_to_v1(_file: &mut Value) -> Result<()> {
+    Ok(())
+}
+
+/// Runs every migration needed to bring `file` up to [`COSMETICS_VERSION`] and returns the version it started at.
+pub fn migrate(file: &mut Value) -> Result<u32> {
+    let from = match file
+        .as_object()
+        .ok_or_else(|| anyhow!("Cosmetics file is not an object"))?
+        .get("version")
+    {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| anyhow!("Invalid cosmetics file version {}", version))?,
+    };
+    if from > COSMETICS_VERSION {
+        return Err(anyhow!(
+            "Cosmetics file version {} is newer than the supported version {}",
+            from,
+            COSMETICS_VERSION
+        )
+        .into());
+    }
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        migration(file)?;
+        file["version"] = json!(version + 1);
+    }
+    Ok(from)
+}
+
+/// Parses a cosmetics file, migrating it to the current version first.
+pub fn parse_cosmetic_file(contents: &str) -> Result<CosmeticFile> {
+    let mut value = serde_json::from_str(contents)?;
+    let from = migrate(&mut value)?;
+    if from != COSMETICS_VERSION {
+        tracing::info!("Migrated cosmetics from version {} to {}", from, COSMETICS_VERSION);
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Upgrades a cosmetics file on disk, the original is kept next to it as `<file>.v<version>.bak`.
+pub fn migrate_file(path: &str) -> Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut value = serde_json::from_str(&contents)?;
+    let from = migrate(&mut value)?;
+    if from == COSMETICS_VERSION {
+        println!("{} is already at version {}", path, COSMETICS_VERSION);
+        return Ok(());
+    }
+
+    // Make sure the result still loads before touching anything
+    let file: CosmeticFile = serde_json::from_value(value)?;
+    let backup = format!("{path}.v{from}.bak");
+    atomic_write(&backup, &contents)?;
+    atomic_write(path, serde_json::to_string_pretty(&file)?)?;
+    println!(
+        "Migrated {} from version {} to {}, the original was backed up to {}",
+        path, from, COSMETICS_VERSION, backup
+    );
+    Ok(())
+}
+
+#[test]
+fn migrates_unversioned_file() {
+    let file =
+        parse_cosmetic_file(r#"{"cosmetics": [], "users": {"41a9b6aa-168a-4be8-8df8-cac17daf7384": {"flags": 32}}}"#)
+            .unwrap();
+    assert_eq!(file.version, COSMETICS_VERSION);
+    assert_eq!(file.users.len(), 1);
+    assert!(parse_cosmetic_file(r#"{"version": 4294967295}"#).is_err());
+}
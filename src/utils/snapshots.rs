@@ -0,0 +1,103 @@
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use serde::Serialize;
+
+use crate::{
+    error::Result,
+    utils::{
+        atomic_write,
+        retrieve_cosmetics::{parse_cosmetic_file, CosmeticFile},
+    },
+};
+
+const PREFIX: &str = "cosmetics-";
+const SUFFIX: &str = ".json";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// Unix timestamp in milliseconds
+    pub created: u128,
+    pub size: u64,
+}
+
+/// Lists the snapshots in `dir`, newest first.
+pub fn list_snapshots(dir: &str) -> Result<Vec<Snapshot>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(created) = parse_name(&name) {
+            snapshots.push(Snapshot {
+                name,
+                created,
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+    snapshots.sort_by_key(|s| Reverse(s.created));
+    Ok(snapshots)
+}
+
+/// Writes `file` as a new snapshot unless it is identical to the newest one, then removes all but the newest `keep`
+/// snapshots. Returns the name of the new snapshot, a `keep` of 0 disables snapshots so nothing is written or removed.
+pub fn write_snapshot(dir: &str, keep: usize, file: &CosmeticFile) -> Result<Option<String>> {
+    if keep == 0 {
+        return Ok(None);
+    }
+    let contents = serde_json::to_string_pretty(file)?;
+    let existing = list_snapshots(dir)?;
+    if let Some(newest) = existing.first() {
+        if fs::read_to_string(snapshot_path(dir, &newest.name)?).ok().as_deref() == Some(contents.as_str()) {
+            return Ok(None);
+        }
+    }
+
+    fs::create_dir_all(dir)?;
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let name = format!("{PREFIX}{created}{SUFFIX}");
+    atomic_write(Path::new(dir).join(&name), contents)?;
+
+    for old in existing.iter().skip(keep.saturating_sub(1)) {
+        fs::remove_file(Path::new(dir).join(&old.name))?;
+    }
+    Ok(Some(name))
+}
+
+pub fn read_snapshot(dir: &str, name: &str) -> Result<CosmeticFile> {
+    parse_cosmetic_file(&fs::read_to_string(snapshot_path(dir, name)?)?)
+}
+
+/// Only accepts names that were generated by [`write_snapshot`] so the path can't escape `dir`.
+fn snapshot_path(dir: &str, name: &str) -> Result<PathBuf> {
+    parse_name(name).ok_or_else(|| anyhow!("Invalid snapshot name {}", name))?;
+    Ok(Path::new(dir).join(name))
+}
+
+fn parse_name(name: &str) -> Option<u128> {
+    name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?.parse().ok()
+}
+
+#[test]
+fn zero_keep_leaves_snapshots_alone() {
+    let dir = std::env::temp_dir().join(format!("dws-snapshots-{}", uuid::Uuid::new_v4()));
+    let dir = dir.to_str().unwrap();
+    let file = parse_cosmetic_file(r#"{"cosmetics": [], "users": {}}"#).unwrap();
+    let name = write_snapshot(dir, 1, &file).unwrap().unwrap();
+
+    assert_eq!(write_snapshot(dir, 0, &file).unwrap(), None);
+    let snapshots = list_snapshots(dir).unwrap();
+    assert_eq!(snapshots.iter().map(|s| &s.name).collect::<Vec<_>>(), [&name]);
+    fs::remove_dir_all(dir).unwrap();
+}